@@ -0,0 +1,85 @@
+//! Host benchmark of the reply framing, against `MockBus`.
+//!
+//! cargo +stable bench --bench dispatch --target x86_64-unknown-linux-gnu
+
+use std::time::{Duration, Instant};
+
+use esp32_std_example::bridge::{mock::MockBus, Bridge};
+use peripheral_bridge::pb::{msg::*, prost::Message};
+
+const REGISTERS: u32 = 256;
+const ROUNDS: u32 = 200;
+
+/// A register dump: one single-byte `Read` per register.
+fn dump() -> MsgBatch {
+    MsgBatch {
+        msgs: vec![Msg {
+            transport: TransportType::WebSocket as i32,
+            bus: BusType::Spi as i32,
+            seqs: (0..REGISTERS)
+                .map(|address| BusOps {
+                    operation: Operation::Read as i32,
+                    address,
+                    data: Some(vec![0]),
+                    ..Default::default()
+                })
+                .collect(),
+        }],
+    }
+}
+
+/// Returns the frames and bytes sent per dump and the time spent per dump.
+async fn run(mut bridge: Bridge<'_>, per_op: bool) -> (usize, usize, Duration) {
+    let payload = dump().encode_to_vec();
+    let (mut frames, mut bytes) = (0, 0);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let mut rsp = bridge.handle(&payload).await;
+        if per_op {
+            // What `ws_task` used to do: one frame for every reply
+            rsp = rsp
+                .into_iter()
+                .flat_map(|batch| batch.msgs)
+                .flat_map(|msg| {
+                    msg.seqs.into_iter().map(move |seq| MsgBatch {
+                        msgs: vec![Msg {
+                            transport: msg.transport,
+                            bus: msg.bus,
+                            seqs: vec![seq],
+                        }],
+                    })
+                })
+                .collect();
+        }
+        frames = rsp.len();
+        bytes = rsp.iter().map(|frame| frame.encode_to_vec().len()).sum();
+    }
+
+    (frames, bytes, start.elapsed() / ROUNDS)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let bridge = || Bridge::new().with_bus(BusType::Spi, MockBus::new());
+
+    println!("{} reads per batch, {} rounds", REGISTERS, ROUNDS);
+    let runs = [
+        ("frame per reply", run(bridge(), true).await),
+        (
+            "threshold 16",
+            run(bridge().with_flush_threshold(16), false).await,
+        ),
+        (
+            "threshold 64",
+            run(bridge().with_flush_threshold(64), false).await,
+        ),
+        ("single frame", run(bridge(), false).await),
+    ];
+    for (name, (frames, bytes, elapsed)) in runs {
+        println!(
+            "{:>16}: {:>4} frames, {:>5} bytes, {:>8.1?} per batch",
+            name, frames, bytes, elapsed
+        );
+    }
+}
@@ -1,10 +1,18 @@
+use esp32_std_example::bridge::{spi::SpiBus, Bridge, OP_WATCH};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    hal::spi::{config, SpiDeviceDriver, SpiDriver, SpiDriverConfig, SPI2},
+    hal::gpio::{IOPin, OutputPin, Pin},
+    hal::spi::{config, SpiDriver, SpiDriverConfig, SPI2},
     hal::units::*,
     // http::{client::EspHttpConnection, Method},
     // wifi::{AuthMethod, BlockingWifi, EspWifi},
 };
+use peripheral_bridge::pb::msg::*;
+
+/// Register block read on each data-ready edge
+const ADDRESS: u32 = 0x00;
+const LEN: u16 = 4;
+
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -20,6 +28,8 @@ fn main() -> anyhow::Result<()> {
     let serial_in = peripherals.pins.gpio2; // SDI
     let serial_out = peripherals.pins.gpio3; // SDO
     let cs = peripherals.pins.gpio4;
+    let drdy = peripherals.pins.gpio5; // Data ready, active high
+    let drdy_gpio = drdy.pin() as u8;
 
     let driver = SpiDriver::new::<SPI2>(
         spi,
@@ -32,16 +42,40 @@ fn main() -> anyhow::Result<()> {
     let config = config::Config::new()
         .baudrate(8.MHz().into())
         .data_mode(config::MODE_3);
-    let mut spi = SpiDeviceDriver::new(&driver, Some(cs), &config)?;
-
-    let mut data = [0u8; 5];
+    // 8-bit address, bit 7 set for reads
+    let spi = SpiBus::new(&driver)
+        .with_device("imu", Some(cs.downgrade_output()), &config)?
+        .with_drdy(drdy.downgrade())?;
+    let watcher = spi.drdy_watcher();
+    let mut bridge = Bridge::new().with_bus(BusType::Spi, spi);
 
     tokio_runtime.block_on(async {
+        // What a host sends to arm the pin: a read of LEN bytes at ADDRESS on
+        // each rising edge
+        let [l0, l1] = LEN.to_le_bytes();
+        let rsp = bridge
+            .dispatch(MsgBatch {
+                msgs: vec![Msg {
+                    transport: TransportType::WebSocket as i32,
+                    bus: BusType::Spi as i32,
+                    seqs: vec![BusOps {
+                        operation: OP_WATCH,
+                        address: ADDRESS,
+                        data: Some(vec![drdy_gpio, 1, l0, l1]),
+                        ..Default::default()
+                    }],
+                }],
+            })
+            .await;
+        log::info!("DRDY armed: {:?}", rsp);
+
         loop {
-            data[0] = 0x00 | 0x80;
-            spi.transfer_in_place_async(&mut data).await.unwrap();
-            log::info!("read_buffer: {:x?}", data);
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            watcher.wait().await;
+            for msg in bridge.events().await.msgs {
+                for seq in msg.seqs {
+                    log::info!("bus {}: {:x?}", msg.bus, seq.data);
+                }
+            }
         }
     });
 
@@ -1,52 +1,16 @@
 use bytes::Bytes;
+use esp32_std_example::net::{backoff::Backoff, store::NetStore, wifi::WifiManager, WifiState};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    hal::peripheral,
     http::{client::EspHttpConnection, Method},
-    wifi::{AuthMethod, BlockingWifi, EspWifi},
+    nvs::EspDefaultNvsPartition,
+    wifi::EspWifi,
 };
 use futures_util::SinkExt;
 
-fn wifi(
-    ssid: &str,
-    passwd: &str,
-    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
-    sysloop: EspSystemEventLoop,
-) -> anyhow::Result<Box<EspWifi<'static>>> {
-    let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.is_empty() {
-        anyhow::bail!("Missing WiFi name")
-    }
-    if passwd.is_empty() {
-        auth_method = AuthMethod::None;
-        log::info!("No password provided, using open network");
-    }
-
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
-
-    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
-        esp_idf_svc::wifi::ClientConfiguration {
-            ssid: ssid
-                .try_into()
-                .expect("Could not parse the given SSID into WiFi config"),
-            password: passwd
-                .try_into()
-                .expect("Could not parse the given password into WiFi config"),
-            auth_method,
-            ..Default::default()
-        },
-    ))?;
-    wifi.start()?;
-    log::info!("Connecting wifi...");
-    wifi.connect()?;
-    log::info!("Waiting for DHCP lease...");
-    wifi.wait_netif_up()?;
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-    log::info!("Wifi DHCP info: {:?}", ip_info);
-
-    Ok(Box::new(esp_wifi))
-}
+/// First and longest wait between two rounds of Wi-Fi connection attempts
+const BACKOFF_INITIAL: std::time::Duration = std::time::Duration::from_millis(500);
+const BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(30);
 
 async fn http_get(url: &str) -> anyhow::Result<EspHttpConnection> {
     let configuration = esp_idf_svc::http::client::Configuration::default();
@@ -62,11 +26,11 @@ fn main() -> anyhow::Result<()> {
     esp_idf_svc::log::EspLogger::initialize_default();
     let peripherals = esp_idf_svc::hal::prelude::Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
     let _fs = esp_idf_svc::io::vfs::MountedEventfs::mount(20)?;
-    //SSID=wifi_name PASSWD=xxx SERVER_URL=ws://host_ip:8765 cargo run --example web
-    let ssid: Option<&str> = option_env!("SSID");
-    let passwd: Option<&str> = option_env!("PASSWD");
-    const SERVER_URL: Option<&str> = option_env!("SERVER_URL");
+    // Networks and server URL stored in NVS, the values given at build time
+    // being the defaults: SSID=wifi_name PASSWD=xxx SERVER_URL=ws://host_ip:8765
+    let config = NetStore::new(nvs)?.load()?;
 
     let tokio_runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -84,15 +48,17 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
-    let _wifi = wifi(
-        ssid.unwrap(),
-        passwd.unwrap_or_default(),
-        peripherals.modem,
-        sysloop,
-    )
-    .unwrap();
+    // Joins the first reachable network and keeps the station connected
+    let esp_wifi = EspWifi::new(peripherals.modem, sysloop.clone(), None)?;
+    // SAFETY: reads the hardware random number generator
+    let seed = unsafe { esp_idf_svc::sys::esp_random() };
+    let backoff = Backoff::new(BACKOFF_INITIAL, BACKOFF_MAX, seed);
+    let wifi = WifiManager::new(esp_wifi, sysloop, config.networks, backoff)?;
+    let mut wifi_state = wifi.state();
+    tokio_runtime.spawn(wifi.run());
 
     tokio_runtime.block_on(async {
+        let _ = wifi_state.wait_for(WifiState::is_up).await;
         log::info!("start HTTP GET request");
         match http_get("http://httpbin.org/get").await {
             Ok(_) => log::info!("HTTP GET request SUCCESS"),
@@ -100,11 +66,11 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
-    if let Some(url) = SERVER_URL {
+    if let Some(url) = &config.server_url {
         log::info!("start WebSocket task to {}", url);
         tokio_runtime.block_on(ws_task(url))?;
     } else {
-        log::warn!("No SERVER_URL provided, skipping WebSocket task");
+        log::warn!("No server URL configured, skipping WebSocket task");
     }
 
     tokio_runtime.block_on(async {
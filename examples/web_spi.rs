@@ -1,56 +1,82 @@
+use std::cell::RefCell;
+use std::net::Ipv4Addr;
+
 use bytes::Bytes;
+use esp32_std_example::bridge::{
+    device::DeviceInfo,
+    drdy::DrdyWatcher,
+    fifo::SampleFifo,
+    gpio::{GpioBus, GpioWatcher},
+    i2c::I2cBus,
+    nvs::NvsScriptStore,
+    spi::SpiBus,
+    stream,
+    uart::UartBus,
+    Bridge, BUS_GPIO, BUS_UART, MAX_PAYLOAD,
+};
+use esp32_std_example::net::{
+    backoff::Backoff,
+    ble::{BleProvisioning, MAX_PASSKEY},
+    config::{endpoint, TlsConfig},
+    delimited, discovery,
+    led::StatusLed,
+    mdns::{Mdns, SERVICE_TCP, SERVICE_WS},
+    portal,
+    store::NetStore,
+    tls,
+    wifi::WifiManager,
+    Link, WifiState,
+};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    hal::peripheral,
-    hal::spi::{config, SpiDeviceDriver, SpiDriver, SpiDriverConfig, SPI2},
+    hal::gpio::{AnyIOPin, IOPin, OutputPin, Pin},
+    hal::i2c::{I2cConfig, I2cDriver},
+    hal::spi::{config, SpiDriver, SpiDriverConfig, SPI2},
+    hal::uart::{config::Config as UartConfig, UartDriver},
     hal::units::*,
     http::{client::EspHttpConnection, Method},
-    wifi::{AuthMethod, BlockingWifi, EspWifi},
+    nvs::EspDefaultNvsPartition,
+    wifi::EspWifi,
 };
-use futures_util::SinkExt;
+use futures_util::{future, Sink, SinkExt, Stream, StreamExt};
 use peripheral_bridge::pb::{msg::*, prost::Message};
-use tokio_websockets::{ClientBuilder, Message as WsMessage};
-
-fn wifi(
-    ssid: &str,
-    passwd: &str,
-    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
-    sysloop: EspSystemEventLoop,
-) -> anyhow::Result<Box<EspWifi<'static>>> {
-    let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.is_empty() {
-        anyhow::bail!("Missing WiFi name")
-    }
-    if passwd.is_empty() {
-        auth_method = AuthMethod::None;
-        log::info!("No password provided, using open network");
-    }
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_websockets::{
+    ClientBuilder, Limits, Message as WsMessage, ServerBuilder, WebSocketStream,
+};
+use ws2812_esp32_rmt_driver::Ws2812Esp32Rmt;
 
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
-
-    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
-        esp_idf_svc::wifi::ClientConfiguration {
-            ssid: ssid
-                .try_into()
-                .expect("Could not parse the given SSID into WiFi config"),
-            password: passwd
-                .try_into()
-                .expect("Could not parse the given password into WiFi config"),
-            auth_method,
-            ..Default::default()
-        },
-    ))?;
-    wifi.start()?;
-    log::info!("Connecting wifi...");
-    wifi.connect()?;
-    log::info!("Waiting for DHCP lease...");
-    wifi.wait_netif_up()?;
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-    log::info!("Wifi DHCP info: {:?}", ip_info);
-
-    Ok(Box::new(esp_wifi))
-}
+/// Replies per WebSocket frame, large register dumps are sent in several frames
+const FLUSH_THRESHOLD: usize = 256;
+
+/// Sample frames kept while the WebSocket is busy, the oldest are dropped
+/// beyond that
+const FIFO_LEN: usize = 128;
+
+/// Reply batches waiting for the WebSocket before requests stop being read
+const REPLY_QUEUE: usize = 4;
+
+/// First and longest wait between two connection attempts, to the Wi-Fi or
+/// to the server
+const BACKOFF_INITIAL: std::time::Duration = std::time::Duration::from_millis(500);
+const BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Access point opened to configure the board
+const PORTAL_SSID: &str = "esp32-bridge";
+
+/// Name advertised by the BLE provisioning service
+const BLE_NAME: &str = "esp32-bridge";
+
+/// mDNS name of the board, followed by the end of its MAC, and ports
+/// listened on when no server URL is configured
+const HOSTNAME: &str = "esp32-bridge";
+const WS_PORT: u16 = 8765;
+const TCP_PORT: u16 = 8766;
+
+/// Script run before connecting, e.g. to initialise the sensors
+const BOOT_SCRIPT: &str = "boot";
 
 async fn http_get(url: &str) -> anyhow::Result<EspHttpConnection> {
     let configuration = esp_idf_svc::http::client::Configuration::default();
@@ -66,11 +92,11 @@ fn main() -> anyhow::Result<()> {
     esp_idf_svc::log::EspLogger::initialize_default();
     let peripherals = esp_idf_svc::hal::prelude::Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
     let _fs = esp_idf_svc::io::vfs::MountedEventfs::mount(20)?;
-    //SSID=wifi_name PASSWD=xxx SERVER_URL=ws://host_ip:8765 cargo run --example web
-    let ssid: Option<&str> = option_env!("SSID");
-    let passwd: Option<&str> = option_env!("PASSWD");
-    const SERVER_URL: Option<&str> = option_env!("SERVER_URL");
+    // Networks and server URL stored in NVS, the values given at build time
+    // being the defaults: SSID=wifi_name PASSWD=xxx SERVER_URL=ws://host_ip:8765
+    let net_config = NetStore::new(nvs.clone())?.load()?;
 
     let tokio_runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -82,6 +108,12 @@ fn main() -> anyhow::Result<()> {
     let serial_in = peripherals.pins.gpio2; // SDI
     let serial_out = peripherals.pins.gpio3; // SDO
     let cs = peripherals.pins.gpio4;
+    let spi_pins = [
+        ("sclk", sclk.pin() as u32),
+        ("sdi", serial_in.pin() as u32),
+        ("sdo", serial_out.pin() as u32),
+        ("cs", cs.pin() as u32),
+    ];
 
     let driver = SpiDriver::new::<SPI2>(
         spi,
@@ -94,12 +126,81 @@ fn main() -> anyhow::Result<()> {
     let config = config::Config::new()
         .baudrate(8.MHz().into())
         .data_mode(config::MODE_3);
-    let spi = SpiDeviceDriver::new(driver, Some(cs), &config)?;
+    // DRDY of the IMU, armed by the host with an OP_WATCH on the SPI bus
+    let drdy = peripherals.pins.gpio18;
+    let spi = SpiBus::new(&driver)
+        .with_device("imu", Some(cs.downgrade_output()), &config)?
+        .with_drdy(drdy.downgrade())?;
+
+    // Configure I2C
+    let sda = peripherals.pins.gpio5;
+    let scl = peripherals.pins.gpio6;
+    let i2c_pins = [("sda", sda.pin() as u32), ("scl", scl.pin() as u32)];
+    let config = I2cConfig::new().baudrate(400.kHz().into());
+    let i2c = I2cDriver::new(peripherals.i2c0, sda, scl, &config)?;
+
+    // Configure UART, 9600 baud is what most GPS modules start with
+    let tx = peripherals.pins.gpio7;
+    let rx = peripherals.pins.gpio10;
+    let uart_pins = [("tx", tx.pin() as u32), ("rx", rx.pin() as u32)];
+    let config = UartConfig::new().baudrate(9600.Hz());
+    let uart = UartDriver::new(
+        peripherals.uart1,
+        tx,
+        rx,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &config,
+    )?;
+
+    // Pins the host may configure, read, drive and watch
+    let gpio = GpioBus::new([peripherals.pins.gpio0.downgrade()]);
+    let watchers = Watchers {
+        gpio: gpio.watcher(),
+        drdy: spi.drdy_watcher(),
+    };
+
+    // Described to hosts in the hello batch sent on each connection
+    let device = DeviceInfo::read()?;
+    log::info!(
+        "{} {} {}",
+        device.chip,
+        device.firmware,
+        device.mac_string()
+    );
+
+    let mut bridge = Bridge::new()
+        .with_bus(BusType::Spi, spi)
+        .with_bus(BusType::I2c, I2cBus::new(i2c))
+        .with_bus(BUS_UART, UartBus::new(uart, 100))
+        .with_bus(BUS_GPIO, gpio)
+        .with_pins(BusType::Spi, &spi_pins)
+        .with_pins(BusType::I2c, &i2c_pins)
+        .with_pins(BUS_UART, &uart_pins)
+        .with_device(device.clone())
+        .with_scripts(NvsScriptStore::new(nvs.clone())?)
+        .with_flush_threshold(FLUSH_THRESHOLD);
+    // Hosts answer a challenge with the secret before anything is served
+    match &net_config.auth_secret {
+        Some(secret) => bridge = bridge.with_auth(secret.as_bytes()),
+        None => log::warn!("No secret set, any host reaching the board is served"),
+    }
+
+    match tokio_runtime.block_on(bridge.run_script(BOOT_SCRIPT)) {
+        Ok(rsp) => log::info!("Boot script: {:?}", rsp),
+        Err(e) => log::info!("No boot script run: {:#}", e),
+    }
+
+    // Connection state on the RGB LED
+    let ws2812 = Ws2812Esp32Rmt::new(peripherals.rmt.channel0, peripherals.pins.gpio8)?;
+    let mut status_led = StatusLed::new(ws2812);
 
     // Configures the button
     let mut button = esp_idf_svc::hal::gpio::PinDriver::input(peripherals.pins.gpio9)?;
     button.set_pull(esp_idf_svc::hal::gpio::Pull::Up)?;
     button.set_interrupt_type(esp_idf_svc::hal::gpio::InterruptType::PosEdge)?;
+    // Held at boot, advertises the BLE provisioning service
+    let provision = button.is_low();
     tokio_runtime.spawn(async move {
         loop {
             let _ = button.wait_for_falling_edge().await;
@@ -108,15 +209,48 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
-    let _wifi = wifi(
-        ssid.unwrap(),
-        passwd.unwrap_or_default(),
-        peripherals.modem,
-        sysloop,
-    )
-    .unwrap();
+    // Without a network, the board is configured from a phone first
+    let mut esp_wifi = EspWifi::new(peripherals.modem, sysloop.clone(), None)?;
+    if net_config.networks.is_empty() {
+        log::warn!(
+            "Starting provisioning portal, join {} to configure",
+            PORTAL_SSID
+        );
+        portal::run(
+            &mut esp_wifi,
+            sysloop.clone(),
+            NetStore::new(nvs.clone())?,
+            PORTAL_SSID,
+        )?;
+    }
+
+    // Joins the first reachable network and keeps the station connected
+    // SAFETY: reads the hardware random number generator
+    let seed = unsafe { esp_idf_svc::sys::esp_random() };
+    let backoff = Backoff::new(BACKOFF_INITIAL, BACKOFF_MAX, seed);
+    let (networks, networks_rx) = watch::channel(net_config.networks.clone());
+    let wifi = WifiManager::new(esp_wifi, sysloop, net_config.networks, backoff)?
+        .with_updates(networks_rx);
+    let mut wifi_state = wifi.state();
+
+    // Networks written over BLE are joined at once, without a reset
+    if provision {
+        let passkey = match option_env!("BLE_PASSKEY") {
+            Some(passkey) => passkey.parse()?,
+            None => {
+                // SAFETY: reads the hardware random number generator
+                let passkey = unsafe { esp_idf_svc::sys::esp_random() } % (MAX_PASSKEY + 1);
+                log::warn!("BLE provisioning passkey: {:06}", passkey);
+                passkey
+            }
+        };
+        let ble = BleProvisioning::start(BLE_NAME, passkey, NetStore::new(nvs.clone())?, networks)?;
+        tokio_runtime.spawn(ble.run(wifi.state()));
+    }
+    tokio_runtime.spawn(wifi.run());
 
     tokio_runtime.block_on(async {
+        let _ = wifi_state.wait_for(WifiState::is_up).await;
         log::info!("start HTTP GET request");
         match http_get("http://httpbin.org/get").await {
             Ok(_) => log::info!("HTTP GET request SUCCESS"),
@@ -124,120 +258,365 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
-    if let Some(url) = SERVER_URL {
+    // Found on the network as esp32-bridge-xxxxxx.local, xxxxxx the end of
+    // the MAC, and described by its info service in either mode
+    let mut mdns = Mdns::new(
+        &discovery::hostname(HOSTNAME, &device),
+        discovery::txt(&device, &bridge.buses()),
+    )?;
+
+    let (link, link_rx) = watch::channel(Link::Offline);
+    if let Some(url) = &net_config.server_url {
         log::info!("start WebSocket task to {}", url);
-        tokio_runtime.block_on(ws_task(url, spi))?;
+        tokio_runtime.block_on(async {
+            tokio::join!(
+                status_led.run(link_rx),
+                supervise(
+                    url,
+                    &net_config.tls,
+                    &mdns,
+                    bridge,
+                    watchers,
+                    wifi_state,
+                    link
+                )
+            )
+        });
     } else {
-        log::warn!("No SERVER_URL provided, skipping WebSocket task");
+        // Without a server to dial, host tools browse for the board and
+        // connect to it
+        log::info!("No server URL configured, listening for host tools");
+        mdns.announce(SERVICE_WS, WS_PORT)?;
+        mdns.announce(SERVICE_TCP, TCP_PORT)?;
+        tokio_runtime.block_on(async {
+            tokio::join!(
+                status_led.run(link_rx),
+                listen(bridge, watchers, wifi_state, link)
+            )
+        });
     }
 
-    tokio_runtime.block_on(async {
-        loop {
-            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-            log::info!("tick");
+    Ok(())
+}
+
+/// What wakes a session up to collect [`Bridge::events`]: an edge on a
+/// watched GPIO or an armed DRDY pin.
+struct Watchers {
+    gpio: GpioWatcher,
+    drdy: DrdyWatcher,
+}
+
+impl Watchers {
+    async fn wait(&self) {
+        tokio::select! {
+            _ = self.gpio.wait() => {}
+            _ = self.drdy.wait() => {}
         }
-    });
+    }
+}
 
-    Ok(())
+/// Keep a WebSocket connection to `url` up, reconnecting after a backoff
+/// whenever it fails or is closed. `wss://` servers are verified with
+/// `tls`.
+async fn supervise(
+    url: &str,
+    tls: &TlsConfig,
+    mdns: &Mdns,
+    mut bridge: Bridge<'_>,
+    watchers: Watchers,
+    mut wifi: watch::Receiver<WifiState>,
+    link: watch::Sender<Link>,
+) -> ! {
+    // SAFETY: reads the hardware random number generator
+    let seed = unsafe { esp_idf_svc::sys::esp_random() };
+    let mut backoff = Backoff::new(BACKOFF_INITIAL, BACKOFF_MAX, seed);
+
+    loop {
+        if !wifi.borrow().is_up() {
+            link.send_replace(Link::Offline);
+            let _ = wifi.wait_for(WifiState::is_up).await;
+        }
+        link.send_replace(Link::Connecting);
+        match ws_task(url, tls, mdns, &mut bridge, &watchers, &mut backoff, &link).await {
+            Ok(()) => log::warn!("WebSocket closed by {}", url),
+            Err(e) => log::error!("WebSocket to {} failed: {:#}", url, e),
+        }
+
+        link.send_replace(Link::Backoff);
+        let delay = backoff.next_delay();
+        log::info!(
+            "Reconnecting in {:?} (attempt {})",
+            delay,
+            backoff.attempt()
+        );
+        tokio::time::sleep(delay).await;
+    }
 }
 
 async fn ws_task(
     url: &str,
-    mut spi: SpiDeviceDriver<'static, SpiDriver<'_>>,
+    tls: &TlsConfig,
+    mdns: &Mdns,
+    bridge: &mut Bridge<'_>,
+    watchers: &Watchers,
+    backoff: &mut Backoff,
+    link: &watch::Sender<Link>,
 ) -> anyhow::Result<()> {
-    use futures_util::StreamExt;
+    // A server named by its service type is looked up on each attempt, it
+    // may have moved
+    let url = match discovery::service(url) {
+        Some(service) => discovery::with_address(url, mdns.resolve(service).await?),
+        None => url.to_owned(),
+    };
+    if url.starts_with("wss://") {
+        let (stream, pump) = tls::connect(&url, tls).await?;
+        tokio::select! {
+            result = pump => result,
+            result = ws_client(&url, stream, bridge, watchers, backoff, link) => result,
+        }
+    } else {
+        let stream = TcpStream::connect(endpoint(&url)?).await?;
+        ws_client(&url, stream, bridge, watchers, backoff, link).await
+    }
+}
 
-    let (mut ws_stream, _) = ClientBuilder::new().uri(url)?.connect().await?;
+/// Open the WebSocket to `url` on `stream`, then serve the host.
+async fn ws_client<S>(
+    url: &str,
+    stream: S,
+    bridge: &mut Bridge<'_>,
+    watchers: &Watchers,
+    backoff: &mut Backoff,
+    link: &watch::Sender<Link>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (ws_stream, _) = ClientBuilder::new()
+        .uri(url)?
+        .limits(limits())
+        .connect_on(stream)
+        .await?;
     log::info!("WebSocket connected to {}", url);
+    backoff.reset();
+    link.send_replace(Link::Connected);
+    let (sink, source) = websocket(ws_stream);
+    session(bridge, watchers, sink, source).await
+}
 
-    while let Some(msg) = ws_stream.next().await {
-        match msg {
-            Ok(msg) => {
-                if msg.is_binary() {
-                    let rx_msgs = msg.into_payload().to_vec();
-                    let rx_msgs = MsgBatch::decode(rx_msgs.as_slice()).unwrap();
-                    for msg in rx_msgs.msgs {
-                        for seq in msg.seqs {
-                            match Operation::try_from(seq.operation).unwrap() {
-                                Operation::Ack => {
-                                    log::info!("Received Ack operation");
-                                }
-                                Operation::Read => {
-                                    // log::info!("Read addr: 0x{:x?}", seq.address);
-                                    if let Some(sequence) = seq.data {
-                                        let mut rx_buf = vec![0; sequence.len() + 1];
-                                        rx_buf[0] = seq.address as u8 | 0x80;
-                                        spi.transfer_in_place_async(&mut rx_buf).await?;
-                                        // log::info!("Spi Read data: {:?}", rx_buf);
-                                        let rsp = MsgBatch {
-                                            msgs: vec![Msg {
-                                                transport: TransportType::WebSocket as i32,
-                                                bus: BusType::Spi as i32,
-                                                seqs: vec![BusOps {
-                                                    operation: Operation::Ack as i32,
-                                                    address: seq.address,
-                                                    data: Some(rx_buf[1..].to_vec()),
-                                                    ..Default::default()
-                                                }],
-                                            }],
-                                        };
-                                        let buffer = rsp.encode_to_vec();
-                                        let buffer = Bytes::from(buffer);
-
-                                        ws_stream.send(WsMessage::binary(buffer)).await?;
-                                    }
-                                }
-                                Operation::Write => {
-                                    // log::info!("Received Write operation");
-                                    if let Some(sequence) = seq.data {
-                                        let mut tx_buf = vec![seq.address as u8];
-                                        tx_buf.extend_from_slice(&sequence);
-                                        spi.transfer_in_place_async(&mut tx_buf).await?;
-
-                                        // let rsp = MsgBatch {
-                                        //     msgs: vec![Msg {
-                                        //         transport: TransportType::WebSocket as i32,
-                                        //         bus: BusType::Spi as i32,
-                                        //         seqs: vec![BusOps {
-                                        //             operation: Operation::Ack as i32,
-                                        //             address: seq.address,
-                                        //             data: None,
-                                        //             delay_us: None,
-                                        //         }],
-                                        //     }],
-                                        // };
-                                        // let buffer = rsp.encode_to_vec();
-                                        // let buffer = Bytes::from(buffer);
-                                        // ws_stream.send(WsMessage::binary(buffer)).await?;
-                                    }
-                                }
-                                Operation::Transfer => {
-                                    // log::info!("Received Transfer operation");
-                                    if let Some(sequence) = seq.data {
-                                        let mut tx_buf = vec![seq.address as u8];
-                                        tx_buf.extend_from_slice(&sequence);
-                                        spi.transfer_in_place_async(&mut tx_buf).await?;
-                                    }
-                                }
-                            }
-
-                            if let Some(delay_us) = seq.delay_us {
-                                log::trace!("delay_us: {}", delay_us);
-                                tokio::time::sleep(std::time::Duration::from_micros(
-                                    delay_us as u64,
-                                ))
-                                .await;
-                            }
-                        }
+/// Serve host tools connecting to the board, one at a time, on a WebSocket
+/// port and a raw TCP port.
+async fn listen(
+    mut bridge: Bridge<'_>,
+    watchers: Watchers,
+    mut wifi: watch::Receiver<WifiState>,
+    link: watch::Sender<Link>,
+) -> ! {
+    let _ = wifi.wait_for(WifiState::is_up).await;
+    loop {
+        link.send_replace(Link::Connecting);
+        if let Err(e) = accept(&mut bridge, &watchers, &link).await {
+            log::error!("Listening failed: {:#}", e);
+            link.send_replace(Link::Backoff);
+            tokio::time::sleep(BACKOFF_MAX).await;
+        }
+    }
+}
+
+async fn accept(
+    bridge: &mut Bridge<'_>,
+    watchers: &Watchers,
+    link: &watch::Sender<Link>,
+) -> anyhow::Result<()> {
+    let ws_listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, WS_PORT)).await?;
+    let tcp_listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, TCP_PORT)).await?;
+    log::info!(
+        "Listening on port {} (WebSocket) and {} (TCP)",
+        WS_PORT,
+        TCP_PORT
+    );
+
+    loop {
+        // Other clients wait in the backlog until this one leaves
+        let result = tokio::select! {
+            accepted = ws_listener.accept() => {
+                let (stream, peer) = accepted?;
+                log::info!("WebSocket client {}", peer);
+                link.send_replace(Link::Connected);
+                match ServerBuilder::new().limits(limits()).accept(stream).await {
+                    Ok(ws_stream) => {
+                        let (sink, source) = websocket(ws_stream);
+                        session(bridge, watchers, sink, source).await
                     }
+                    Err(e) => Err(e.into()),
                 }
             }
-            Err(e) => {
-                log::error!("WebSocket error: {}", e);
-                break;
+            accepted = tcp_listener.accept() => {
+                let (stream, peer) = accepted?;
+                log::info!("TCP client {}", peer);
+                link.send_replace(Link::Connected);
+                let (sink, source) = tcp(stream);
+                session(bridge, watchers, sink, source).await
             }
+        };
+        match result {
+            Ok(()) => log::info!("Client left"),
+            Err(e) => log::warn!("Client failed: {:#}", e),
         }
+        link.send_replace(Link::Connecting);
+    }
+}
+
+/// Frames beyond what the bridge accepts close the WebSocket.
+fn limits() -> Limits {
+    Limits::default().max_payload_len(Some(MAX_PAYLOAD))
+}
+
+/// The binary messages of a WebSocket, as encoded batches.
+fn websocket<S>(
+    ws_stream: WebSocketStream<S>,
+) -> (
+    impl Sink<Vec<u8>, Error = anyhow::Error>,
+    impl Stream<Item = anyhow::Result<Vec<u8>>>,
+)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (sink, source) = ws_stream.split();
+    let sink = sink
+        .sink_map_err(anyhow::Error::from)
+        .with(|payload: Vec<u8>| future::ready(Ok(WsMessage::binary(Bytes::from(payload)))));
+    let source = source.filter_map(|msg| {
+        future::ready(match msg {
+            Ok(msg) if msg.is_binary() => Some(Ok(msg.into_payload().to_vec())),
+            Ok(_) => None,
+            Err(e) => Some(Err(e.into())),
+        })
+    });
+    (sink, source)
+}
+
+/// The length-prefixed frames of a raw TCP connection, as encoded batches.
+fn tcp(
+    stream: TcpStream,
+) -> (
+    impl Sink<Vec<u8>, Error = anyhow::Error>,
+    impl Stream<Item = anyhow::Result<Vec<u8>>>,
+) {
+    let (read, write) = stream.into_split();
+    let sink = futures_util::sink::unfold(write, |mut write, payload: Vec<u8>| async move {
+        write.write_all(&delimited::encode(&payload)).await?;
+        anyhow::Ok(write)
+    });
+    let source = futures_util::stream::try_unfold(
+        (read, delimited::Decoder::new()),
+        |(mut read, mut decoder)| async move {
+            loop {
+                if let Some(payload) = decoder.decode()? {
+                    return anyhow::Ok(Some((payload, (read, decoder))));
+                }
+                let mut buf = [0; 512];
+                match read.read(&mut buf).await? {
+                    0 => return Ok(None),
+                    n => decoder.extend(&buf[..n]),
+                }
+            }
+        },
+    );
+    (sink, source)
+}
+
+/// Serve the host at the other end of `sink` and `source` until it leaves:
+/// requests are answered, samples and events streamed.
+async fn session<Si, St>(
+    bridge: &mut Bridge<'_>,
+    watchers: &Watchers,
+    sink: Si,
+    source: St,
+) -> anyhow::Result<()>
+where
+    Si: Sink<Vec<u8>, Error = anyhow::Error>,
+    St: Stream<Item = anyhow::Result<Vec<u8>>>,
+{
+    let mut sink = std::pin::pin!(sink);
+    let mut source = std::pin::pin!(source);
+
+    sink.send(bridge.hello().encode_to_vec()).await?;
+
+    // Replies wait for the socket, samples and events are queued in the FIFO
+    // so sampling goes on while a frame is being sent
+    let (replies_tx, mut replies) = tokio::sync::mpsc::channel::<MsgBatch>(REPLY_QUEUE);
+    let fifo = RefCell::new(SampleFifo::new(FIFO_LEN));
+    let queued = tokio::sync::Notify::new();
+    let queue = |batch: MsgBatch| {
+        fifo.borrow_mut().push(stream::now_us(), batch);
+        queued.notify_one();
+    };
+
+    let receiver = async {
+        loop {
+            tokio::select! {
+                msg = source.next() => match msg {
+                    Some(Ok(rx_msgs)) => {
+                        for rsp in bridge.handle(&rx_msgs).await {
+                            replies_tx.send(rsp).await?;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        log::error!("Receive failed: {:#}", e);
+                        break;
+                    }
+                    None => break,
+                },
+                _ = watchers.wait() => queue(bridge.events().await),
+                _ = sleep_until(bridge.next_sample()) => {
+                    queue(bridge.sample(std::time::Instant::now()).await)
+                }
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    let sender = async {
+        loop {
+            let rsp = tokio::select! {
+                biased;
+                rsp = replies.recv() => match rsp {
+                    Some(rsp) => rsp,
+                    None => break,
+                },
+                _ = queued.notified() => {
+                    let mut fifo = fifo.borrow_mut();
+                    let Some(batch) = fifo.drain(FLUSH_THRESHOLD) else {
+                        continue;
+                    };
+                    if fifo.has_pending() {
+                        queued.notify_one();
+                    }
+                    batch
+                }
+            };
+
+            sink.send(rsp.encode_to_vec()).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    tokio::select! {
+        result = receiver => result?,
+        result = sender => result?,
+    }
+    if fifo.borrow().overflows() > 0 {
+        log::warn!("{} sample frames dropped", fifo.borrow().overflows());
     }
 
     Ok(())
 }
+
+/// Sleep until `deadline`, forever if there is none.
+async fn sleep_until(deadline: Option<std::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
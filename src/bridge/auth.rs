@@ -0,0 +1,98 @@
+//! Challenge-response authentication of the host, see
+//! [`BUS_AUTH`](super::BUS_AUTH).
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Random bytes of a challenge.
+pub const CHALLENGE_LEN: usize = 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The secret shared with the host and where the current session stands.
+pub struct Auth {
+    secret: Vec<u8>,
+    challenge: Option<[u8; CHALLENGE_LEN]>,
+    authenticated: bool,
+}
+
+impl Auth {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+            challenge: None,
+            authenticated: false,
+        }
+    }
+
+    /// Start a new session, unauthenticated, and return its first
+    /// challenge.
+    pub fn start(&mut self) -> [u8; CHALLENGE_LEN] {
+        self.authenticated = false;
+        self.challenge()
+    }
+
+    /// A fresh challenge, replacing the pending one.
+    pub fn challenge(&mut self) -> [u8; CHALLENGE_LEN] {
+        let challenge = random();
+        self.challenge = Some(challenge);
+        challenge
+    }
+
+    /// Authenticate the session if `answer` is the [`answer`] to the pending
+    /// challenge. The challenge is used up either way.
+    pub fn verify(&mut self, answer: &[u8]) -> anyhow::Result<()> {
+        let Some(challenge) = self.challenge.take() else {
+            fail!(InvalidArgument, "no challenge pending, read one first");
+        };
+        let mut mac = mac(&self.secret);
+        mac.update(&challenge);
+        if mac.verify_slice(answer).is_err() {
+            log::warn!("Host failed to authenticate");
+            fail!(Unauthenticated, "wrong answer to the challenge");
+        }
+        log::info!("Host authenticated");
+        self.authenticated = true;
+        Ok(())
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+}
+
+/// What the host answers to `challenge`: its HMAC-SHA256 keyed with
+/// `secret`.
+pub fn answer(secret: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let mut mac = mac(secret);
+    mac.update(challenge);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length")
+}
+
+/// Random bytes from the hardware generator.
+#[cfg(target_os = "espidf")]
+fn random() -> [u8; CHALLENGE_LEN] {
+    let mut buf = [0; CHALLENGE_LEN];
+    // SAFETY: `buf` is valid for `buf.len()` bytes
+    unsafe { esp_idf_svc::sys::esp_fill_random(buf.as_mut_ptr().cast(), buf.len()) };
+    buf
+}
+
+/// Bytes from the randomly keyed hasher of std, unpredictable enough for
+/// host builds, which only talk to the mock buses.
+#[cfg(not(target_os = "espidf"))]
+fn random() -> [u8; CHALLENGE_LEN] {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut buf = [0; CHALLENGE_LEN];
+    for chunk in buf.chunks_mut(8) {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(super::stream::now_us());
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+    buf
+}
@@ -0,0 +1,84 @@
+//! What a bus offers, told to the host in the
+//! [`hello`](super::Bridge::hello) batch.
+
+use peripheral_bridge::pb::msg::Operation;
+
+use super::op_name;
+
+/// The operations a bus serves, the pins wired to it and the devices on it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `BusOps.operation` values served, e.g. [`OP_CONFIG`](super::OP_CONFIG).
+    pub operations: Vec<i32>,
+    /// Pins by role, e.g. `("sclk", 1)`.
+    pub pins: Vec<(String, u32)>,
+    /// Names of the devices [`OP_SELECT`](super::OP_SELECT) switches
+    /// between.
+    pub devices: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn new(operations: impl IntoIterator<Item = i32>) -> Self {
+        Self {
+            operations: operations.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// `Read`, `Write` and `Transfer`, what every [`BusBackend`](super::BusBackend)
+    /// implements.
+    pub fn registers() -> Self {
+        Self::new([
+            Operation::Read as i32,
+            Operation::Write as i32,
+            Operation::Transfer as i32,
+        ])
+    }
+
+    pub fn with_operations(mut self, operations: impl IntoIterator<Item = i32>) -> Self {
+        self.operations.extend(operations);
+        self
+    }
+
+    pub fn with_pins<S: Into<String>>(mut self, pins: impl IntoIterator<Item = (S, u32)>) -> Self {
+        self.pins
+            .extend(pins.into_iter().map(|(role, pin)| (role.into(), pin)));
+        self
+    }
+
+    pub fn with_devices<S: Into<String>>(mut self, devices: impl IntoIterator<Item = S>) -> Self {
+        self.devices.extend(devices.into_iter().map(Into::into));
+        self
+    }
+
+    /// As the data of an [`OP_HELLO`](super::OP_HELLO): `key=value` lines,
+    /// `ops=read,write`, `pins=sclk:1,sdo:3` and `devices=imu`, the empty
+    /// ones left out.
+    pub fn encode(&self) -> Vec<u8> {
+        let ops: Vec<String> = self
+            .operations
+            .iter()
+            .map(|op| op_name(*op).map_or_else(|| op.to_string(), str::to_owned))
+            .collect();
+        let pins: Vec<String> = self
+            .pins
+            .iter()
+            .map(|(role, pin)| format!("{}:{}", role, pin))
+            .collect();
+        lines(&[
+            ("ops", ops.join(",")),
+            ("pins", pins.join(",")),
+            ("devices", self.devices.join(",")),
+        ])
+    }
+}
+
+/// `entries` as `key=value` lines, those with an empty value left out.
+pub(super) fn lines(entries: &[(&str, String)]) -> Vec<u8> {
+    entries
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect::<String>()
+        .into_bytes()
+}
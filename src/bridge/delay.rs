@@ -0,0 +1,152 @@
+//! `BusOps.delay_us` handling.
+//!
+//! A thread sleep is rounded to the scheduler tick, 10 ms with the default
+//! 100 Hz FreeRTOS tick, which is far too coarse for the microsecond delays
+//! of power-up sequences. Short delays are therefore busy-waited and long
+//! ones slept for whole ticks, the remainder being busy-waited as well.
+//!
+//! The sleep is a tokio one, so the other tasks of the runtime, Wi-Fi, LED
+//! and samples, go on meanwhile. Only the busy-wait holds the thread.
+
+use std::time::{Duration, Instant};
+
+/// How a delay is served: first slept, then busy-waited until the deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Plan {
+    pub sleep: Duration,
+    pub spin: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DelayScheduler {
+    tick: Duration,
+}
+
+impl Default for DelayScheduler {
+    /// Uses the FreeRTOS tick on the ESP32 and 1 ms elsewhere.
+    fn default() -> Self {
+        #[cfg(target_os = "espidf")]
+        let tick = Duration::from_micros(1_000_000 / esp_idf_svc::sys::configTICK_RATE_HZ as u64);
+        #[cfg(not(target_os = "espidf"))]
+        let tick = Duration::from_millis(1);
+
+        Self::new(tick)
+    }
+}
+
+impl DelayScheduler {
+    pub const fn new(tick: Duration) -> Self {
+        Self { tick }
+    }
+
+    /// Split `delay` in a sleep and a busy-wait.
+    ///
+    /// A sleep of `n` ticks may end anywhere in its last tick, so one tick is
+    /// kept as margin: delays under two ticks are entirely busy-waited.
+    pub fn plan(&self, delay: Duration) -> Plan {
+        if self.tick.is_zero() || delay < self.tick * 2 {
+            return Plan {
+                sleep: Duration::ZERO,
+                spin: delay,
+            };
+        }
+
+        let ticks = (delay.as_nanos() / self.tick.as_nanos()) as u32 - 1;
+        let sleep = self.tick * ticks;
+        Plan {
+            sleep,
+            spin: delay - sleep,
+        }
+    }
+
+    /// Wait for `delay` and return the time actually waited, more than
+    /// `delay` when other tasks held the thread past the sleep.
+    pub async fn wait(&self, delay: Duration) -> Duration {
+        let start = Instant::now();
+        let plan = self.plan(delay);
+
+        if !plan.sleep.is_zero() {
+            tokio::time::sleep(plan.sleep).await;
+        }
+        while start.elapsed() < delay {
+            core::hint::spin_loop();
+        }
+
+        start.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn plan(delay: Duration) -> Plan {
+        DelayScheduler::new(TICK).plan(delay)
+    }
+
+    #[test]
+    fn zero_tick_spins() {
+        let delay = Duration::from_millis(50);
+        assert_eq!(
+            DelayScheduler::new(Duration::ZERO).plan(delay),
+            Plan {
+                sleep: Duration::ZERO,
+                spin: delay,
+            }
+        );
+    }
+
+    #[test]
+    fn under_two_ticks_spins() {
+        for delay in [
+            Duration::ZERO,
+            Duration::from_micros(5),
+            TICK,
+            TICK * 2 - Duration::from_nanos(1),
+        ] {
+            assert_eq!(plan(delay).sleep, Duration::ZERO);
+            assert_eq!(plan(delay).spin, delay);
+        }
+    }
+
+    #[test]
+    fn two_ticks_sleeps_one() {
+        assert_eq!(
+            plan(TICK * 2),
+            Plan {
+                sleep: TICK,
+                spin: TICK,
+            }
+        );
+    }
+
+    #[test]
+    fn large_delay_sleeps_all_but_a_tick() {
+        assert_eq!(
+            plan(Duration::from_secs(1)),
+            Plan {
+                sleep: TICK * 99,
+                spin: TICK,
+            }
+        );
+        assert_eq!(
+            plan(Duration::from_micros(1_005_000)),
+            Plan {
+                sleep: TICK * 99,
+                spin: Duration::from_millis(15),
+            }
+        );
+    }
+
+    #[test]
+    fn sleep_and_spin_add_up() {
+        for us in (0..100_000).step_by(1_237) {
+            let delay = Duration::from_micros(us);
+            let plan = plan(delay);
+            assert_eq!(plan.sleep + plan.spin, delay);
+            assert!(plan.spin < TICK * 2);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Identity of the board, announced to hosts, see [`DeviceInfo`].
+
+/// What a host needs to tell boards apart and know what runs on them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Chip model, e.g. `esp32c3`.
+    pub chip: &'static str,
+    /// Version of the firmware.
+    pub firmware: &'static str,
+    /// MAC address of the Wi-Fi station.
+    pub mac: [u8; 6],
+}
+
+impl DeviceInfo {
+    /// The MAC as `aa:bb:cc:dd:ee:ff`.
+    pub fn mac_string(&self) -> String {
+        self.mac
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    /// The board the firmware runs on.
+    #[cfg(target_os = "espidf")]
+    pub fn read() -> anyhow::Result<Self> {
+        use esp_idf_svc::sys::{esp, esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac};
+
+        let chip = core::ffi::CStr::from_bytes_with_nul(esp_idf_svc::sys::CONFIG_IDF_TARGET)?;
+        let mut mac = [0; 6];
+        // SAFETY: `mac` holds the 6 bytes written for a station MAC
+        esp!(unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) })?;
+        Ok(Self {
+            chip: chip.to_str()?,
+            firmware: env!("CARGO_PKG_VERSION"),
+            mac,
+        })
+    }
+}
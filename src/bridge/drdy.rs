@@ -0,0 +1,165 @@
+//! SPI burst reads triggered by the data-ready pin of a sensor, see
+//! [`SpiBus::with_drdy`](super::spi::SpiBus::with_drdy).
+//!
+//! A [`Stream`](super::stream::Stream) on a GPIO runs its whole batch
+//! through the dispatcher. A DRDY pin armed on the SPI bus only counts and
+//! timestamps the edges in its interrupt handler, and the network task,
+//! woken by the [`DrdyWatcher`], reads a single register block right away,
+//! so samples follow the sensor clock instead of the sleep loop of a
+//! polling task.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, Pin as _, PinDriver};
+use esp_idf_svc::hal::interrupt::asynch::HalIsrNotification;
+
+use super::stream::Sample;
+
+/// Wakes up the network task when an armed DRDY pin fired, so it can call
+/// [`Bridge::events`](super::Bridge::events).
+#[derive(Clone)]
+pub struct DrdyWatcher {
+    notification: Arc<HalIsrNotification>,
+}
+
+impl DrdyWatcher {
+    pub(super) fn new() -> Self {
+        Self {
+            notification: Arc::new(HalIsrNotification::new()),
+        }
+    }
+
+    pub async fn wait(&self) {
+        self.notification.wait().await;
+    }
+}
+
+/// The register block read on each trigger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Burst {
+    /// Index of the device on the bus.
+    pub device: usize,
+    pub address: u32,
+    pub len: usize,
+}
+
+/// A data-ready pin and what it triggers.
+pub struct Drdy {
+    gpio: u32,
+    pin: PinDriver<'static, AnyIOPin, Input>,
+    /// Triggers seen by the interrupt handler and not read yet.
+    pending: Arc<AtomicU32>,
+    /// Time of the last trigger, in [`now_us`](super::stream::now_us) µs.
+    triggered_us: Arc<AtomicU64>,
+    notification: Arc<HalIsrNotification>,
+    burst: Option<Burst>,
+    /// Whether the trigger is an edge, re-armed before the read.
+    edge: bool,
+    skipped: u32,
+}
+
+impl Drdy {
+    /// Take `pin`, reporting its triggers to `watcher`.
+    pub fn new(pin: AnyIOPin, watcher: &DrdyWatcher) -> anyhow::Result<Self> {
+        Ok(Self {
+            gpio: pin.pin() as u32,
+            pin: PinDriver::input(pin)?,
+            pending: Arc::new(AtomicU32::new(0)),
+            triggered_us: Arc::new(AtomicU64::new(0)),
+            notification: watcher.notification.clone(),
+            burst: None,
+            edge: false,
+            skipped: 0,
+        })
+    }
+
+    pub fn gpio(&self) -> u32 {
+        self.gpio
+    }
+
+    /// Read `burst` on each `trigger`, `None` disarms the pin. Level
+    /// triggers fire again as soon as they are re-armed while the level
+    /// holds, which catches up after a late read.
+    pub fn arm(&mut self, trigger: Option<InterruptType>, burst: Burst) -> anyhow::Result<()> {
+        self.pin.unsubscribe()?;
+        self.pending.store(0, Ordering::SeqCst);
+        self.burst = None;
+        self.skipped = 0;
+        let Some(trigger) = trigger else {
+            log::info!("DRDY GPIO{} disarmed", self.gpio);
+            return Ok(());
+        };
+
+        self.pin.set_interrupt_type(trigger)?;
+        let pending = self.pending.clone();
+        let triggered_us = self.triggered_us.clone();
+        let notification = self.notification.clone();
+        // SAFETY: the callback only reads the timer, touches atomics and the
+        // ISR-safe notification, which is what the interrupt context allows.
+        unsafe {
+            self.pin.subscribe(move || {
+                let now = esp_idf_svc::sys::esp_timer_get_time() as u64;
+                triggered_us.store(now, Ordering::SeqCst);
+                pending.fetch_add(1, Ordering::SeqCst);
+                notification.notify_lsb();
+            })?;
+        }
+        self.pin.enable_interrupt()?;
+        self.burst = Some(burst);
+        self.edge = matches!(
+            trigger,
+            InterruptType::PosEdge | InterruptType::NegEdge | InterruptType::AnyEdge
+        );
+        log::info!("DRDY GPIO{} armed: {:?} on {:?}", self.gpio, burst, trigger);
+        Ok(())
+    }
+
+    /// Read the burst with `read` if the pin fired since the last call.
+    ///
+    /// The sample is of stream id the GPIO number, timestamped with the last
+    /// trigger. The driver masks the interrupt after each trigger: an edge
+    /// trigger is re-armed before the read, so the edges that come during
+    /// it are counted and those beyond one reported as skipped by the next
+    /// sample, along with the reads that failed. A level trigger is only
+    /// re-armed once the read is done, so it does not fire again before the
+    /// sensor cleared its DRDY, and only failed reads count as skipped.
+    pub fn poll(&mut self, read: impl FnOnce(Burst) -> anyhow::Result<Vec<u8>>) -> Option<Sample> {
+        let burst = self.burst?;
+        // Still masked, the interrupt handler leaves both alone until re-armed
+        let pending = self.pending.swap(0, Ordering::SeqCst);
+        if pending == 0 {
+            return None;
+        }
+        let timestamp_us = self.triggered_us.load(Ordering::SeqCst);
+        self.skipped = self.skipped.saturating_add(pending - 1);
+
+        if self.edge {
+            self.rearm();
+        }
+        let result = read(burst);
+        if !self.edge {
+            self.rearm();
+        }
+        match result {
+            Ok(data) => Some(Sample {
+                id: self.gpio,
+                address: burst.address,
+                timestamp_us,
+                skipped: std::mem::take(&mut self.skipped),
+                data,
+            }),
+            Err(e) => {
+                log::error!("DRDY GPIO{} read failed: {:#}", self.gpio, e);
+                self.skipped = self.skipped.saturating_add(1);
+                None
+            }
+        }
+    }
+
+    fn rearm(&mut self) {
+        if let Err(e) = self.pin.enable_interrupt() {
+            log::error!("DRDY GPIO{} interrupt not re-armed: {}", self.gpio, e);
+        }
+    }
+}
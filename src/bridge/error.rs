@@ -0,0 +1,56 @@
+//! Error codes reported to the host in [`OP_NACK`](super::OP_NACK) replies.
+
+use core::fmt;
+
+/// First byte of the data of an [`OP_NACK`](super::OP_NACK), followed by
+/// the UTF-8 error message.
+///
+/// Backends return plain `anyhow` errors, which are reported as [`ErrorCode::Bus`],
+/// unless they were built with [`ErrorCode::err`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// The frame is not a valid `MsgBatch`.
+    Malformed = 1,
+    /// No backend is registered for `Msg.bus`.
+    UnknownBus = 2,
+    /// `BusOps.operation` is not a known operation.
+    UnknownOperation = 3,
+    /// The backend does not implement this operation.
+    Unsupported = 4,
+    /// The operation is known but its address or data are invalid.
+    InvalidArgument = 5,
+    /// The bus or the device failed the transaction.
+    Bus = 6,
+    /// The host has not authenticated, see [`BUS_AUTH`](super::BUS_AUTH).
+    Unauthenticated = 7,
+}
+
+impl ErrorCode {
+    /// Wrap `message` into an error reported to the host with this code.
+    pub fn err(self, message: impl fmt::Display + Send + Sync + 'static) -> anyhow::Error {
+        anyhow::Error::new(self).context(message)
+    }
+
+    /// The code of `e`, [`ErrorCode::Bus`] unless it was built with [`ErrorCode::err`].
+    pub fn of(e: &anyhow::Error) -> Self {
+        e.downcast_ref::<Self>().copied().unwrap_or(Self::Bus)
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Malformed => "malformed batch",
+            Self::UnknownBus => "unknown bus",
+            Self::UnknownOperation => "unknown operation",
+            Self::Unsupported => "unsupported operation",
+            Self::InvalidArgument => "invalid argument",
+            Self::Bus => "bus error",
+            Self::Unauthenticated => "not authenticated",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::error::Error for ErrorCode {}
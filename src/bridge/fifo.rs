@@ -0,0 +1,245 @@
+//! Samples waiting for the network, see [`SampleFifo`].
+
+use std::collections::VecDeque;
+
+use peripheral_bridge::pb::msg::*;
+
+use super::{stream, ADDRESS_MASK, BUS_STREAM};
+
+/// Stream id of the overflow reports of [`SampleFifo::drain`], not available
+/// to subscriptions.
+pub const OVERFLOW_ID: u32 = ADDRESS_MASK;
+
+/// The samples or events produced at once, e.g. by
+/// [`Bridge::sample`](super::Bridge::sample), and when they were queued, in
+/// µs since boot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub timestamp_us: u64,
+    pub batch: MsgBatch,
+}
+
+impl Frame {
+    fn ops(&self) -> usize {
+        self.batch.msgs.iter().map(|msg| msg.seqs.len()).sum()
+    }
+}
+
+/// Ring buffer of the [`Frame`]s produced while the network is busy.
+///
+/// When full, the oldest frame makes room for the new one: a stalled
+/// connection loses the oldest samples rather than the latest, and the
+/// host is told how many with the next batch drained.
+#[derive(Debug)]
+pub struct SampleFifo {
+    frames: VecDeque<Frame>,
+    capacity: usize,
+    /// Frames dropped since the last overflow report.
+    dropped: u32,
+    last_drop_us: u64,
+    overflows: u64,
+}
+
+impl SampleFifo {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+            last_drop_us: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Queue `batch`, produced at `timestamp_us`. Empty batches are ignored.
+    pub fn push(&mut self, timestamp_us: u64, batch: MsgBatch) {
+        if batch.msgs.is_empty() {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            if let Some(oldest) = self.frames.pop_front() {
+                self.dropped = self.dropped.saturating_add(1);
+                self.last_drop_us = oldest.timestamp_us;
+                self.overflows += 1;
+            }
+        }
+        self.frames.push_back(Frame {
+            timestamp_us,
+            batch,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Whether [`drain`](Self::drain) has something to return, frames or
+    /// an overflow report, even when [`is_empty`](Self::is_empty).
+    pub fn has_pending(&self) -> bool {
+        !self.frames.is_empty() || self.dropped > 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Frames dropped since the FIFO was created.
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Take the oldest frames, as many as fit in `max_ops` operations but at
+    /// least one, merged in a single batch.
+    ///
+    /// If frames were dropped since the last call, the batch starts with an
+    /// overflow report: a `Msg` on [`BUS_STREAM`] holding the
+    /// [`stream::header`] of stream [`OVERFLOW_ID`], with the timestamp of
+    /// the last frame dropped and how many were.
+    pub fn drain(&mut self, max_ops: usize) -> Option<MsgBatch> {
+        if !self.has_pending() {
+            return None;
+        }
+
+        let mut batch = MsgBatch::default();
+        let mut ops = 0;
+        if self.dropped > 0 {
+            batch.msgs.push(Msg {
+                transport: TransportType::WebSocket as i32,
+                bus: BUS_STREAM,
+                seqs: vec![stream::header(
+                    OVERFLOW_ID,
+                    self.last_drop_us,
+                    std::mem::take(&mut self.dropped),
+                )],
+            });
+            ops += 1;
+        }
+
+        while let Some(frame) = self.frames.front() {
+            let len = frame.ops();
+            if ops > 0 && ops + len > max_ops {
+                break;
+            }
+            ops += len;
+            if let Some(frame) = self.frames.pop_front() {
+                batch.msgs.extend(frame.batch.msgs);
+            }
+        }
+
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame of `ops` reads on the SPI bus, tagged with `id`.
+    fn batch(id: u32, ops: usize) -> MsgBatch {
+        MsgBatch {
+            msgs: vec![Msg {
+                transport: TransportType::WebSocket as i32,
+                bus: BusType::Spi as i32,
+                seqs: (0..ops)
+                    .map(|_| BusOps {
+                        operation: Operation::Ack as i32,
+                        address: id,
+                        ..Default::default()
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    /// The ids of the frames of `batch`, overflow reports left out.
+    fn ids(batch: &MsgBatch) -> Vec<u32> {
+        batch
+            .msgs
+            .iter()
+            .filter(|msg| msg.bus != BUS_STREAM)
+            .map(|msg| msg.seqs[0].address)
+            .collect()
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut fifo = SampleFifo::new(3);
+        for id in 0..5 {
+            fifo.push(id as u64 * 10, batch(id, 1));
+        }
+
+        assert_eq!(fifo.len(), 3);
+        assert_eq!(fifo.capacity(), 3);
+        assert_eq!(fifo.overflows(), 2);
+        assert_eq!(ids(&fifo.drain(usize::MAX).unwrap()), [2, 3, 4]);
+        assert!(fifo.is_empty());
+        assert!(!fifo.has_pending());
+        assert_eq!(fifo.overflows(), 2);
+    }
+
+    #[test]
+    fn reports_overflow_first() {
+        let mut fifo = SampleFifo::new(2);
+        for id in 0..5 {
+            fifo.push(100 + id as u64, batch(id, 1));
+        }
+
+        let drained = fifo.drain(usize::MAX).unwrap();
+        assert_eq!(drained.msgs[0].bus, BUS_STREAM);
+        assert_eq!(drained.msgs[0].seqs, [stream::header(OVERFLOW_ID, 102, 3)]);
+        assert_eq!(ids(&drained), [3, 4]);
+
+        // Reported once
+        fifo.push(200, batch(5, 1));
+        let drained = fifo.drain(usize::MAX).unwrap();
+        assert_eq!(drained.msgs.len(), 1);
+        assert_eq!(ids(&drained), [5]);
+        assert_eq!(fifo.overflows(), 3);
+    }
+
+    #[test]
+    fn reports_overflow_without_frames() {
+        let mut fifo = SampleFifo::new(1);
+        fifo.push(1, batch(0, 1));
+        fifo.push(2, batch(1, 1));
+        assert_eq!(ids(&fifo.drain(1).unwrap()), []);
+
+        assert!(fifo.has_pending());
+        assert_eq!(ids(&fifo.drain(1).unwrap()), [1]);
+        assert!(fifo.drain(1).is_none());
+    }
+
+    #[test]
+    fn drain_packs_max_ops() {
+        let mut fifo = SampleFifo::new(8);
+        fifo.push(0, batch(0, 2));
+        fifo.push(1, batch(1, 2));
+        fifo.push(2, batch(2, 5));
+        fifo.push(3, batch(3, 1));
+
+        assert_eq!(ids(&fifo.drain(4).unwrap()), [0, 1]);
+        // Larger than `max_ops`, sent on its own
+        assert_eq!(ids(&fifo.drain(4).unwrap()), [2]);
+        assert_eq!(ids(&fifo.drain(4).unwrap()), [3]);
+        assert!(fifo.drain(4).is_none());
+
+        fifo.push(4, batch(4, 3));
+        assert_eq!(ids(&fifo.drain(0).unwrap()), [4]);
+    }
+
+    #[test]
+    fn ignores_empty_batches() {
+        let mut fifo = SampleFifo::new(1);
+        fifo.push(0, batch(0, 1));
+        fifo.push(1, MsgBatch::default());
+
+        assert_eq!(fifo.len(), 1);
+        assert_eq!(fifo.overflows(), 0);
+        assert_eq!(ids(&fifo.drain(8).unwrap()), [0]);
+    }
+}
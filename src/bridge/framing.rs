@@ -0,0 +1,295 @@
+//! How a register access is laid out on the SPI wire.
+
+/// Framing profile of an SPI device: what is clocked out before the data of
+/// a register access.
+///
+/// The header is an optional command byte, then the register address MSB
+/// first with the read or write flag and the auto-increment flag OR-ed in,
+/// then, for reads only, dummy bytes. [`Framing::new`] is the 8-bit address,
+/// bit 7 read flag convention of most IMUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    pub address_bytes: u8,
+    pub read_flag: u16,
+    pub write_flag: u16,
+    pub auto_increment: u16,
+    pub dummy_bytes: u8,
+    pub read_command: Option<u8>,
+    pub write_command: Option<u8>,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framing {
+    pub const fn new() -> Self {
+        Self {
+            address_bytes: 1,
+            read_flag: 0x80,
+            write_flag: 0,
+            auto_increment: 0,
+            dummy_bytes: 0,
+            read_command: None,
+            write_command: None,
+        }
+    }
+
+    /// Width of the address, `0`, `1` or `2` bytes.
+    #[must_use]
+    pub const fn address_bytes(mut self, address_bytes: u8) -> Self {
+        self.address_bytes = address_bytes;
+        self
+    }
+
+    /// Bits set in the address of reads, `0` for devices that flag writes.
+    #[must_use]
+    pub const fn read_flag(mut self, read_flag: u16) -> Self {
+        self.read_flag = read_flag;
+        self
+    }
+
+    /// Bits set in the address of writes, e.g. for read-bit-low devices.
+    #[must_use]
+    pub const fn write_flag(mut self, write_flag: u16) -> Self {
+        self.write_flag = write_flag;
+        self
+    }
+
+    /// Bits set in the address when more than one byte is accessed.
+    #[must_use]
+    pub const fn auto_increment(mut self, auto_increment: u16) -> Self {
+        self.auto_increment = auto_increment;
+        self
+    }
+
+    /// Bytes clocked between the address and the data of a read.
+    #[must_use]
+    pub const fn dummy_bytes(mut self, dummy_bytes: u8) -> Self {
+        self.dummy_bytes = dummy_bytes;
+        self
+    }
+
+    /// Command byte sent before the address of reads.
+    #[must_use]
+    pub const fn read_command(mut self, read_command: Option<u8>) -> Self {
+        self.read_command = read_command;
+        self
+    }
+
+    /// Command byte sent before the address of writes and transfers.
+    #[must_use]
+    pub const fn write_command(mut self, write_command: Option<u8>) -> Self {
+        self.write_command = write_command;
+        self
+    }
+
+    /// Decode a profile sent by the host:
+    /// `[address_bytes, dummy_bytes, read_flag, write_flag, auto_increment]`
+    /// with the flags as 16-bit little endian, optionally followed by the read
+    /// and write command bytes.
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let (header, commands) = data.split_at(data.len().min(8));
+        let [address_bytes, dummy_bytes, r0, r1, w0, w1, i0, i1] = *header else {
+            fail!(InvalidArgument, "framing needs 8 bytes, got {}", data.len());
+        };
+        if commands.len() > 2 {
+            fail!(
+                InvalidArgument,
+                "framing has {} trailing bytes",
+                commands.len()
+            );
+        }
+
+        let framing = Self::new()
+            .address_bytes(address_bytes)
+            .dummy_bytes(dummy_bytes)
+            .read_flag(u16::from_le_bytes([r0, r1]))
+            .write_flag(u16::from_le_bytes([w0, w1]))
+            .auto_increment(u16::from_le_bytes([i0, i1]))
+            .read_command(commands.first().copied())
+            .write_command(commands.get(1).copied());
+        if framing.address_bytes > 2 {
+            fail!(
+                InvalidArgument,
+                "address width {} bytes",
+                framing.address_bytes
+            );
+        }
+        let width = framing.address_bytes as u32 * 8;
+        for (name, flag) in [
+            ("read flag", framing.read_flag),
+            ("write flag", framing.write_flag),
+            ("auto-increment flag", framing.auto_increment),
+        ] {
+            if flag as u32 >> width != 0 {
+                fail!(
+                    InvalidArgument,
+                    "{} 0x{:x} does not fit in {} address bytes",
+                    name,
+                    flag,
+                    framing.address_bytes
+                );
+            }
+        }
+        Ok(framing)
+    }
+
+    /// Header of a read of `len` bytes at `address`.
+    pub fn read_header(&self, address: u32, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut header = self.header(self.read_command, address, self.read_flag, len)?;
+        header.resize(header.len() + self.dummy_bytes as usize, 0);
+        Ok(header)
+    }
+
+    /// Header of a write or a transfer of `len` bytes at `address`.
+    pub fn write_header(&self, address: u32, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.header(self.write_command, address, self.write_flag, len)
+    }
+
+    fn header(
+        &self,
+        command: Option<u8>,
+        address: u32,
+        flag: u16,
+        len: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        if self.address_bytes > 2 {
+            fail!(
+                InvalidArgument,
+                "address width {} bytes",
+                self.address_bytes
+            );
+        }
+        if address >> (self.address_bytes as u32 * 8) != 0 {
+            fail!(
+                InvalidArgument,
+                "address 0x{:x} does not fit in {} bytes",
+                address,
+                self.address_bytes
+            );
+        }
+
+        let mut address = address | flag as u32;
+        if len > 1 {
+            address |= self.auto_increment as u32;
+        }
+        let mut header: Vec<u8> = command.into_iter().collect();
+        header.extend_from_slice(&address.to_be_bytes()[4 - self.address_bytes as usize..]);
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::ErrorCode;
+
+    fn rejected(result: anyhow::Result<impl core::fmt::Debug>) -> ErrorCode {
+        ErrorCode::of(&result.unwrap_err())
+    }
+
+    #[test]
+    fn flags_reads_of_8_bit_addresses() {
+        let framing = Framing::new();
+        assert_eq!(framing.read_header(0x0f, 1).unwrap(), [0x8f]);
+        assert_eq!(framing.write_header(0x0f, 1).unwrap(), [0x0f]);
+        assert_eq!(
+            rejected(framing.read_header(0x100, 1)),
+            ErrorCode::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn sends_16_bit_addresses_msb_first() {
+        let framing = Framing::new()
+            .address_bytes(2)
+            .read_flag(0)
+            .write_flag(0x8000);
+        assert_eq!(framing.read_header(0x1234, 1).unwrap(), [0x12, 0x34]);
+        assert_eq!(framing.write_header(0x1234, 1).unwrap(), [0x92, 0x34]);
+        assert_eq!(
+            rejected(framing.write_header(0x1_0000, 1)),
+            ErrorCode::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn sends_commands_before_the_address() {
+        let framing = Framing::new()
+            .address_bytes(0)
+            .read_flag(0)
+            .read_command(Some(0x03))
+            .write_command(Some(0x02));
+        assert_eq!(framing.read_header(0, 4).unwrap(), [0x03]);
+        assert_eq!(framing.write_header(0, 4).unwrap(), [0x02]);
+        assert_eq!(
+            rejected(framing.read_header(1, 1)),
+            ErrorCode::InvalidArgument
+        );
+
+        let framing = framing.address_bytes(1);
+        assert_eq!(framing.read_header(0x20, 1).unwrap(), [0x03, 0x20]);
+    }
+
+    #[test]
+    fn pads_reads_with_dummy_bytes() {
+        let framing = Framing::new().dummy_bytes(2);
+        assert_eq!(framing.read_header(0x0f, 1).unwrap(), [0x8f, 0, 0]);
+        assert_eq!(framing.write_header(0x0f, 1).unwrap(), [0x0f]);
+    }
+
+    #[test]
+    fn auto_increments_multi_byte_accesses() {
+        let framing = Framing::new().auto_increment(0x40);
+        assert_eq!(framing.read_header(0x28, 1).unwrap(), [0xa8]);
+        assert_eq!(framing.read_header(0x28, 6).unwrap(), [0xe8]);
+        assert_eq!(framing.write_header(0x20, 2).unwrap(), [0x60]);
+    }
+
+    #[test]
+    fn decodes_host_profiles() {
+        let framing = Framing::decode(&[2, 1, 0, 0, 0, 0x80, 0x00, 0x40]).unwrap();
+        assert_eq!(
+            framing,
+            Framing::new()
+                .address_bytes(2)
+                .dummy_bytes(1)
+                .read_flag(0)
+                .write_flag(0x8000)
+                .auto_increment(0x4000)
+        );
+
+        let framing = Framing::decode(&[1, 0, 0x80, 0, 0, 0, 0, 0, 0x0b, 0x02]).unwrap();
+        assert_eq!(framing.read_command, Some(0x0b));
+        assert_eq!(framing.write_command, Some(0x02));
+    }
+
+    #[test]
+    fn rejects_invalid_profiles() {
+        for data in [
+            &[1, 0, 0x80, 0, 0, 0, 0][..],
+            &[1, 0, 0x80, 0, 0, 0, 0, 0, 1, 2, 3],
+            &[3, 0, 0, 0, 0, 0, 0, 0],
+        ] {
+            assert_eq!(rejected(Framing::decode(data)), ErrorCode::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn rejects_flags_wider_than_the_address() {
+        for data in [
+            // Read flag 0x100 on an 8-bit address
+            [1, 0, 0x00, 0x01, 0, 0, 0, 0],
+            // Write flag on no address at all
+            [0, 0, 0, 0, 0x80, 0, 0, 0],
+            // Auto-increment 0x100 on an 8-bit address
+            [1, 0, 0x80, 0, 0, 0, 0x00, 0x01],
+        ] {
+            assert_eq!(rejected(Framing::decode(&data)), ErrorCode::InvalidArgument);
+        }
+        assert!(Framing::decode(&[2, 0, 0x00, 0x80, 0, 0, 0x00, 0x40]).is_ok());
+    }
+}
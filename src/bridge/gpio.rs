@@ -0,0 +1,233 @@
+//! GPIO backend: pin configuration, levels and edge notifications.
+//!
+//! `BusOps.address` is the GPIO number. The host drives a pin with:
+//!
+//! * [`OP_CONFIG`](super::OP_CONFIG), data `[mode, pull]` with mode `0` input,
+//!   `1` output, `2` open-drain output and pull `0` none, `1` up, `2` down,
+//!   `3` up and down;
+//! * `Write`, data `[level]`;
+//! * `Read`, any one byte of data, replied with `[level]`;
+//! * [`OP_WATCH`](super::OP_WATCH), data `[edge]` with `0` off, `1` rising,
+//!   `2` falling, `3` any. Each edge is later reported as an
+//!   [`OP_EVENT`](super::OP_EVENT) holding `[level]`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use esp_idf_svc::hal::gpio::{
+    AnyIOPin, Input, InputOutput, InterruptType, Level, Pin as _, PinDriver, Pull,
+};
+use esp_idf_svc::hal::interrupt::asynch::HalIsrNotification;
+
+use super::{BusBackend, Capabilities, ErrorCode, OP_CONFIG, OP_WATCH};
+
+enum Pin {
+    Unused(AnyIOPin),
+    Input(PinDriver<'static, AnyIOPin, Input>),
+    Output(PinDriver<'static, AnyIOPin, InputOutput>),
+}
+
+/// Edges seen by the interrupt handlers and not yet reported to the host.
+struct Edges {
+    /// One bit per GPIO, split in two words as 64-bit atomics are missing on
+    /// the ESP32-C3.
+    pending: [AtomicU32; 2],
+    notification: HalIsrNotification,
+}
+
+/// Wakes up the network task when a watched pin saw an edge, so it can call
+/// [`Bridge::events`](super::Bridge::events).
+#[derive(Clone)]
+pub struct GpioWatcher {
+    edges: Arc<Edges>,
+}
+
+impl GpioWatcher {
+    pub async fn wait(&self) {
+        self.edges.notification.wait().await;
+    }
+}
+
+pub struct GpioBus {
+    pins: Vec<(u32, Option<Pin>)>,
+    edges: Arc<Edges>,
+}
+
+impl GpioBus {
+    /// Only `pins` can be reached from the bridge, the rest of the chip is
+    /// left to the firmware.
+    pub fn new(pins: impl IntoIterator<Item = AnyIOPin>) -> Self {
+        Self {
+            pins: pins
+                .into_iter()
+                .map(|pin| (pin.pin() as u32, Some(Pin::Unused(pin))))
+                .collect(),
+            edges: Arc::new(Edges {
+                pending: [AtomicU32::new(0), AtomicU32::new(0)],
+                notification: HalIsrNotification::new(),
+            }),
+        }
+    }
+
+    pub fn watcher(&self) -> GpioWatcher {
+        GpioWatcher {
+            edges: self.edges.clone(),
+        }
+    }
+
+    fn slot(&mut self, gpio: u32) -> anyhow::Result<&mut Option<Pin>> {
+        self.pins
+            .iter_mut()
+            .find(|(n, _)| *n == gpio)
+            .map(|(_, pin)| pin)
+            .ok_or_else(|| {
+                ErrorCode::InvalidArgument
+                    .err(format!("GPIO{} is not available to the bridge", gpio))
+            })
+    }
+
+    fn pin(&mut self, gpio: u32) -> anyhow::Result<&mut Pin> {
+        self.slot(gpio)?
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("GPIO{} was lost in a failed reconfiguration", gpio))
+    }
+}
+
+impl BusBackend for GpioBus {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let level = match self.pin(address)? {
+            Pin::Unused(_) => fail!(InvalidArgument, "GPIO{} is not configured", address),
+            Pin::Input(driver) => driver.get_level(),
+            Pin::Output(driver) => driver.get_level(),
+        };
+        if let Some(b) = buf.first_mut() {
+            *b = level as u8;
+        }
+        Ok(buf.len().min(1))
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let Some(level) = data.first() else {
+            fail!(InvalidArgument, "missing level");
+        };
+        match self.pin(address)? {
+            Pin::Output(driver) => driver.set_level(Level::from(*level != 0))?,
+            _ => fail!(InvalidArgument, "GPIO{} is not an output", address),
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, address: u32, data: &mut [u8]) -> anyhow::Result<usize> {
+        self.write(address, data)?;
+        self.read(address, data)
+    }
+
+    fn configure(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let [mode, pull] = data else {
+            fail!(
+                InvalidArgument,
+                "expected [mode, pull], got {} bytes",
+                data.len()
+            );
+        };
+        let pull = match pull {
+            0 => Pull::Floating,
+            1 => Pull::Up,
+            2 => Pull::Down,
+            3 => Pull::UpDown,
+            _ => fail!(InvalidArgument, "invalid pull {}", pull),
+        };
+
+        let slot = self.slot(address)?;
+        let Some(mut pin) = slot.take() else {
+            anyhow::bail!("GPIO{} was lost in a failed reconfiguration", address);
+        };
+        if let Pin::Input(driver) = &mut pin {
+            let _ = driver.unsubscribe();
+        }
+
+        let pin = match (mode, pin) {
+            (0, Pin::Unused(pin)) => Pin::Input(PinDriver::input(pin)?),
+            (0, Pin::Input(driver)) => Pin::Input(driver),
+            (0, Pin::Output(driver)) => Pin::Input(driver.into_input()?),
+            (1, Pin::Unused(pin)) => Pin::Output(PinDriver::input_output(pin)?),
+            (1, Pin::Input(driver)) => Pin::Output(driver.into_input_output()?),
+            (1, Pin::Output(driver)) => Pin::Output(driver.into_input_output()?),
+            (2, Pin::Unused(pin)) => Pin::Output(PinDriver::input_output_od(pin)?),
+            (2, Pin::Input(driver)) => Pin::Output(driver.into_input_output_od()?),
+            (2, Pin::Output(driver)) => Pin::Output(driver.into_input_output_od()?),
+            (mode, pin) => {
+                *slot = Some(pin);
+                fail!(InvalidArgument, "invalid mode {}", mode);
+            }
+        };
+
+        let pin = slot.insert(pin);
+        match pin {
+            Pin::Input(driver) => driver.set_pull(pull)?,
+            Pin::Output(driver) => driver.set_pull(pull)?,
+            Pin::Unused(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn watch(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let edge = match data.first() {
+            Some(0) => None,
+            Some(1) => Some(InterruptType::PosEdge),
+            Some(2) => Some(InterruptType::NegEdge),
+            Some(3) => Some(InterruptType::AnyEdge),
+            _ => fail!(InvalidArgument, "expected [edge]"),
+        };
+        let edges = self.edges.clone();
+        let Pin::Input(driver) = self.pin(address)? else {
+            fail!(InvalidArgument, "GPIO{} is not an input", address);
+        };
+
+        driver.unsubscribe()?;
+        let Some(edge) = edge else {
+            return Ok(());
+        };
+        driver.set_interrupt_type(edge)?;
+        let (word, bit) = ((address / 32) as usize, 1 << (address % 32));
+        // SAFETY: the callback only touches atomics and the ISR-safe
+        // notification, which is what the interrupt context allows.
+        unsafe {
+            driver.subscribe(move || {
+                edges.pending[word].fetch_or(bit, Ordering::SeqCst);
+                edges.notification.notify_lsb();
+            })?;
+        }
+        driver.enable_interrupt()?;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::registers()
+            .with_operations([OP_CONFIG, OP_WATCH])
+            .with_pins(self.pins.iter().map(|(gpio, _)| ("io", *gpio)))
+    }
+
+    fn events(&mut self) -> Vec<(u32, Vec<u8>)> {
+        let pending = [
+            self.edges.pending[0].swap(0, Ordering::SeqCst),
+            self.edges.pending[1].swap(0, Ordering::SeqCst),
+        ];
+        let mut events = Vec::new();
+
+        for (gpio, pin) in &mut self.pins {
+            if pending[(*gpio / 32) as usize] & (1 << (*gpio % 32)) == 0 {
+                continue;
+            }
+            if let Some(Pin::Input(driver)) = pin {
+                events.push((*gpio, vec![driver.get_level() as u8]));
+                // The driver masks the interrupt after each edge
+                if let Err(e) = driver.enable_interrupt() {
+                    log::error!("GPIO{} interrupt not re-armed: {}", gpio, e);
+                }
+            }
+        }
+
+        events
+    }
+}
@@ -0,0 +1,72 @@
+//! I2C backend on top of `I2cDriver`.
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::i2c::I2cDriver;
+use esp_idf_svc::sys::TickType_t;
+
+use super::BusBackend;
+
+/// How long a single I2C transaction may take before it is reported as failed.
+const TIMEOUT_MS: u64 = 100;
+
+/// Register access on an I2C bus.
+///
+/// `BusOps.address` carries the 7-bit device address in bits 8..15 and the
+/// register in bits 0..7, so `0x6a0f` reads register `0x0f` of device `0x6a`.
+/// A device above `0x7f` or a missing ACK from the device is returned as an
+/// error.
+pub struct I2cBus<'d> {
+    driver: I2cDriver<'d>,
+}
+
+impl<'d> I2cBus<'d> {
+    pub fn new(driver: I2cDriver<'d>) -> Self {
+        Self { driver }
+    }
+
+    /// The device and register of `address`, rejecting devices wider than
+    /// 7 bits instead of addressing another one.
+    fn split(address: u32) -> anyhow::Result<(u8, u8)> {
+        let device = address >> 8;
+        if device > 0x7f {
+            fail!(
+                InvalidArgument,
+                "I2C device 0x{:x} does not fit in 7 bits",
+                device
+            );
+        }
+        Ok((device as u8, address as u8))
+    }
+
+    fn timeout() -> TickType_t {
+        TickType::new_millis(TIMEOUT_MS).ticks()
+    }
+}
+
+impl BusBackend for I2cBus<'_> {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let (device, register) = Self::split(address)?;
+        self.driver
+            .write_read(device, &[register], buf, Self::timeout())?;
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let (device, register) = Self::split(address)?;
+        let mut tx_buf = vec![register];
+        tx_buf.extend_from_slice(data);
+        self.driver.write(device, &tx_buf, Self::timeout())?;
+        Ok(())
+    }
+
+    /// Write `data` to the register, then read the same number of bytes back
+    /// after a repeated start.
+    fn transfer(&mut self, address: u32, data: &mut [u8]) -> anyhow::Result<usize> {
+        let (device, register) = Self::split(address)?;
+        let mut tx_buf = vec![register];
+        tx_buf.extend_from_slice(data);
+        self.driver
+            .write_read(device, &tx_buf, data, Self::timeout())?;
+        Ok(data.len())
+    }
+}
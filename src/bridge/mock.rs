@@ -0,0 +1,56 @@
+//! In-memory bus used to exercise the bridge without hardware.
+
+use super::BusBackend;
+
+/// A device with 256 byte-wide registers and auto-incrementing addresses,
+/// enough to stand in for the IMU on a host.
+pub struct MockBus {
+    pub regs: [u8; 256],
+    /// Number of bus accesses performed so far.
+    pub accesses: usize,
+}
+
+impl Default for MockBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        Self {
+            regs: [0; 256],
+            accesses: 0,
+        }
+    }
+
+    fn reg(address: u32, offset: usize) -> usize {
+        (address as usize + offset) & 0xff
+    }
+}
+
+impl BusBackend for MockBus {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> anyhow::Result<usize> {
+        self.accesses += 1;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.regs[Self::reg(address, i)];
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        self.accesses += 1;
+        for (i, b) in data.iter().enumerate() {
+            self.regs[Self::reg(address, i)] = *b;
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, address: u32, data: &mut [u8]) -> anyhow::Result<usize> {
+        self.accesses += 1;
+        for (i, b) in data.iter_mut().enumerate() {
+            std::mem::swap(b, &mut self.regs[Self::reg(address, i)]);
+        }
+        Ok(data.len())
+    }
+}
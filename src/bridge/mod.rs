@@ -0,0 +1,1149 @@
+//! Peripheral bridge: turns a `MsgBatch` received from the host into bus
+//! operations and collects the replies into a `MsgBatch` to send back.
+//!
+//! The dispatcher only talks to [`BusBackend`], so the same code drives the
+//! real SPI controller on the ESP32 and [`mock::MockBus`] on a Linux host.
+
+use peripheral_bridge::pb::{msg::*, prost::Message};
+
+use self::auth::Auth;
+use self::delay::DelayScheduler;
+use self::device::DeviceInfo;
+use self::script::ScriptStore;
+use self::stream::{Sample, Stream, Trigger};
+
+/// `anyhow::bail!` for errors reported to the host with a given [`ErrorCode`].
+macro_rules! fail {
+    ($code:ident, $($arg:tt)*) => {
+        return Err($crate::bridge::ErrorCode::$code.err(format!($($arg)*)))
+    };
+}
+
+pub mod auth;
+mod capabilities;
+pub mod delay;
+pub mod device;
+#[cfg(target_os = "espidf")]
+pub mod drdy;
+mod error;
+pub mod fifo;
+pub mod framing;
+#[cfg(target_os = "espidf")]
+pub mod gpio;
+#[cfg(target_os = "espidf")]
+pub mod i2c;
+pub mod mock;
+#[cfg(target_os = "espidf")]
+pub mod nvs;
+pub mod script;
+pub mod settings;
+#[cfg(target_os = "espidf")]
+pub mod spi;
+pub mod stream;
+#[cfg(target_os = "espidf")]
+pub mod uart;
+
+pub use capabilities::Capabilities;
+pub use error::ErrorCode;
+
+/// Reply sent instead of an `Ack` when an operation failed, e.g. an I2C
+/// device that did not acknowledge its address. The data holds the
+/// [`ErrorCode`] followed by a human readable message.
+///
+/// `Operation` has no such variant, so the value is taken from outside the
+/// range used by `peripheral_bridge`.
+pub const OP_NACK: i32 = 0x100;
+
+/// Backend specific configuration, see [`BusBackend::configure`].
+pub const OP_CONFIG: i32 = 0x101;
+
+/// Subscribe to asynchronous notifications, see [`BusBackend::watch`].
+pub const OP_WATCH: i32 = 0x102;
+
+/// Notification pushed to the host without a matching request, see
+/// [`Bridge::events`].
+pub const OP_EVENT: i32 = 0x103;
+
+/// Pick the device the rest of the `Msg` talks to, see [`BusBackend::select`].
+pub const OP_SELECT: i32 = 0x104;
+
+/// Run the script named by the data, see [`BUS_SCRIPT`].
+pub const OP_RUN: i32 = 0x105;
+
+/// Sent by the device when it connects, see [`Bridge::hello`].
+pub const OP_HELLO: i32 = 0x106;
+
+/// Bits of `BusOps.address` holding the target address.
+///
+/// The upper half is a tag chosen by the host: it is not seen by the backends
+/// and comes back unchanged in the reply, so several reads of the same
+/// register can be told apart and requests pipelined. See [`tag`].
+pub const ADDRESS_MASK: u32 = 0xffff;
+
+/// The host tag carried by `BusOps.address`.
+pub fn tag(address: u32) -> u16 {
+    (address >> 16) as u16
+}
+
+/// `Msg.bus` value for the UART passthrough, which `BusType` does not cover.
+pub const BUS_UART: i32 = 0x100;
+
+/// `Msg.bus` value for GPIO access.
+pub const BUS_GPIO: i32 = 0x101;
+
+/// `Msg.bus` value for the scripts stored on the device, an encoded
+/// `MsgBatch` kept under a name and run on request, e.g. the initialisation
+/// of a sensor. Served by the [`Bridge`] itself from its
+/// [`ScriptStore`]:
+///
+/// - `Write` stores `[name_len, name, script]`, an empty script deletes it,
+/// - `Read` with the name as data replies the stored script,
+/// - [`OP_RUN`] with the name as data runs it and replies its result batch,
+///   encoded, see [`Bridge::run_script`].
+pub const BUS_SCRIPT: i32 = 0x102;
+
+/// `Msg.bus` value for streams, read sequences the device repeats by itself
+/// and pushes to the host. Served by the [`Bridge`] itself:
+///
+/// - [`OP_WATCH`] subscribes to the `MsgBatch` in its data, see
+///   [`Stream::decode`], replacing any stream with the same address, and an
+///   empty data unsubscribes,
+/// - each sample is a `Msg` on this bus holding the [`Stream::sample`]
+///   header, with the address of the subscription, followed by the replies
+///   to the batch.
+///
+/// Samples are taken by [`Bridge::sample`] and [`Bridge::events`]. When they
+/// are not called in time, samples are skipped rather than taken late, and
+/// the header of the next sample tells how many. A [`fifo::SampleFifo`]
+/// keeps sampling going while the network is busy.
+pub const BUS_STREAM: i32 = 0x103;
+
+/// `Msg.bus` value for authenticating the host, when the [`Bridge`] has a
+/// secret, see [`Bridge::with_auth`]. Served by the [`Bridge`] itself:
+///
+/// - `Read` replies a fresh challenge, [`auth::CHALLENGE_LEN`] random bytes,
+///   the first one being sent in the [`hello`](Bridge::hello) batch,
+/// - `Write` with the HMAC-SHA256 of the challenge, keyed with the secret,
+///   authenticates the session, see [`auth::answer`]. A challenge is only
+///   answered once, right or wrong.
+///
+/// Until then, every other operation is answered with
+/// [`ErrorCode::Unauthenticated`] and no sample or event is sent.
+pub const BUS_AUTH: i32 = 0x104;
+
+/// `Msg.bus` value of the board itself, only found in the
+/// [`hello`](Bridge::hello) batch, where its [`OP_HELLO`] describes the
+/// device.
+pub const BUS_DEVICE: i32 = 0x105;
+
+/// Largest encoded `MsgBatch` the host may send in one frame.
+pub const MAX_PAYLOAD: usize = 64 * 1024;
+
+/// Short name of `bus`, e.g. `spi`, for the announcements of the board.
+pub fn bus_name(bus: i32) -> Option<&'static str> {
+    Some(match bus {
+        BUS_UART => "uart",
+        BUS_GPIO => "gpio",
+        BUS_SCRIPT => "script",
+        BUS_STREAM => "stream",
+        BUS_AUTH => "auth",
+        bus if bus == BusType::Spi as i32 => "spi",
+        bus if bus == BusType::I2c as i32 => "i2c",
+        _ => return None,
+    })
+}
+
+/// Short name of operation `op`, e.g. `read`, for the capabilities of the
+/// buses.
+pub fn op_name(op: i32) -> Option<&'static str> {
+    Some(match op {
+        OP_NACK => "nack",
+        OP_CONFIG => "config",
+        OP_WATCH => "watch",
+        OP_EVENT => "event",
+        OP_SELECT => "select",
+        OP_RUN => "run",
+        OP_HELLO => "hello",
+        op if op == Operation::Ack as i32 => "ack",
+        op if op == Operation::Read as i32 => "read",
+        op if op == Operation::Write as i32 => "write",
+        op if op == Operation::Transfer as i32 => "transfer",
+        _ => return None,
+    })
+}
+
+/// A bus the bridge can forward register accesses to.
+///
+/// `address` is the target part of `BusOps.address` (see [`ADDRESS_MASK`]),
+/// each backend decides how to put it on the wire.
+pub trait BusBackend {
+    /// Read up to `buf.len()` bytes starting at `address` and return how
+    /// many were read. Register buses always fill `buf`.
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> anyhow::Result<usize>;
+
+    /// Write `data` starting at `address`.
+    fn write(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Full-duplex transfer: `data` is sent after `address` and overwritten
+    /// with the bytes clocked in. Returns how many bytes of `data` are valid.
+    fn transfer(&mut self, address: u32, data: &mut [u8]) -> anyhow::Result<usize>;
+
+    /// Handle an [`OP_CONFIG`] operation.
+    fn configure(&mut self, _address: u32, _data: &[u8]) -> anyhow::Result<()> {
+        fail!(Unsupported, "configuration not supported on this bus")
+    }
+
+    /// Handle an [`OP_WATCH`] operation.
+    fn watch(&mut self, _address: u32, _data: &[u8]) -> anyhow::Result<()> {
+        fail!(Unsupported, "watch not supported on this bus")
+    }
+
+    /// Called before the operations of each `Msg`.
+    fn begin(&mut self) {}
+
+    /// Handle an [`OP_SELECT`] operation, `address` is the index of the
+    /// device unless `data` holds its name.
+    fn select(&mut self, _address: u32, _data: &[u8]) -> anyhow::Result<()> {
+        fail!(Unsupported, "a single device on this bus")
+    }
+
+    /// Drain the `(address, data)` notifications raised since the last call.
+    fn events(&mut self) -> Vec<(u32, Vec<u8>)> {
+        Vec::new()
+    }
+
+    /// Drain the samples read by the backend itself since the last call,
+    /// e.g. on the data-ready pin of a sensor.
+    fn samples(&mut self) -> Vec<Sample> {
+        Vec::new()
+    }
+
+    /// What the host is told about this bus in the [`Bridge::hello`]
+    /// batch, by default the register operations alone.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::registers()
+    }
+}
+
+/// Routes each `Msg` of a batch to the backend registered for its `bus`.
+pub struct Bridge<'d> {
+    buses: Vec<(i32, Box<dyn BusBackend + 'd>)>,
+    flush_threshold: Option<usize>,
+    delay: DelayScheduler,
+    scripts: Option<Box<dyn ScriptStore + 'd>>,
+    running: bool,
+    streams: Vec<Stream>,
+    auth: Option<Auth>,
+    device: Option<DeviceInfo>,
+    pins: Vec<(i32, String, u32)>,
+}
+
+impl Default for Bridge<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> Bridge<'d> {
+    pub fn new() -> Self {
+        Self {
+            buses: Vec::new(),
+            flush_threshold: None,
+            delay: DelayScheduler::default(),
+            scripts: None,
+            running: false,
+            streams: Vec::new(),
+            auth: None,
+            device: None,
+            pins: Vec::new(),
+        }
+    }
+
+    /// Register `backend` for messages addressed to `bus`, replacing any
+    /// previous backend for the same bus.
+    ///
+    /// `bus` is either a `BusType` or one of the `BUS_*` extension ids.
+    pub fn with_bus(mut self, bus: impl Into<i32>, backend: impl BusBackend + 'd) -> Self {
+        let bus = bus.into();
+        self.buses.retain(|(b, _)| *b != bus);
+        self.buses.push((bus, Box::new(backend)));
+        self
+    }
+
+    /// Cut the replies to a batch into frames of at most `ops` operations,
+    /// instead of a single frame however large the batch is.
+    pub fn with_flush_threshold(mut self, ops: usize) -> Self {
+        self.flush_threshold = Some(ops.max(1));
+        self
+    }
+
+    /// Serve `delay_us` with `delay` instead of the scheduler for the
+    /// current target.
+    pub fn with_delay(mut self, delay: DelayScheduler) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Keep the scripts of [`BUS_SCRIPT`] in `store`.
+    pub fn with_scripts(mut self, store: impl ScriptStore + 'd) -> Self {
+        self.scripts = Some(Box::new(store));
+        self
+    }
+
+    /// Serve hosts only once they proved they know `secret`, see
+    /// [`BUS_AUTH`].
+    pub fn with_auth(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.auth = Some(Auth::new(secret));
+        self
+    }
+
+    /// Describe the board as `device` in the [`hello`](Self::hello) batch.
+    pub fn with_device(mut self, device: DeviceInfo) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// Tell the host `bus` is wired to `pins`, by role, e.g.
+    /// `[("sclk", 1), ("sdo", 3)]`, in the [`hello`](Self::hello) batch.
+    pub fn with_pins(mut self, bus: impl Into<i32>, pins: &[(&str, u32)]) -> Self {
+        let bus = bus.into();
+        self.pins
+            .extend(pins.iter().map(|(role, pin)| (bus, role.to_string(), *pin)));
+        self
+    }
+
+    /// Whether operations from the host are served: no secret is set, the
+    /// host authenticated, or a script is running.
+    fn authenticated(&self) -> bool {
+        match &self.auth {
+            Some(auth) => self.running || auth.is_authenticated(),
+            None => true,
+        }
+    }
+
+    fn backend(&mut self, bus: i32) -> Option<&mut (dyn BusBackend + 'd)> {
+        self.buses
+            .iter_mut()
+            .find(|(b, _)| *b == bus)
+            .map(|(_, backend)| backend.as_mut())
+    }
+
+    /// Decode a frame received from the host, [`dispatch`](Self::dispatch)
+    /// it and return the reply [`frames`](Self::frames) to send back. A frame
+    /// that does not decode is answered with a single [`ErrorCode::Malformed`]
+    /// reply.
+    pub async fn handle(&mut self, payload: &[u8]) -> Vec<MsgBatch> {
+        match MsgBatch::decode(payload) {
+            Ok(batch) => {
+                let rsp = self.dispatch(batch).await;
+                self.frames(rsp)
+            }
+            Err(e) => {
+                log::error!("Dropping malformed batch: {}", e);
+                vec![MsgBatch {
+                    msgs: vec![Msg {
+                        transport: TransportType::WebSocket as i32,
+                        bus: 0,
+                        seqs: vec![nack(0, &ErrorCode::Malformed.err(e))],
+                    }],
+                }]
+            }
+        }
+    }
+
+    /// Execute every operation of `batch` in order and return the replies.
+    ///
+    /// Each `Msg` of `batch` is answered by a `Msg` on the same bus holding,
+    /// in order, one reply per operation: an `Ack`, with the data read for
+    /// `Read` and `Transfer`, or an [`OP_NACK`]. Replies carry the `address`
+    /// of their request, tag included, and when the request had a `delay_us`
+    /// the time actually waited after it, in µs. `Ack`s sent by the host are
+    /// replies themselves and are not answered.
+    ///
+    /// Delays are awaited, see [`DelayScheduler::wait`], operations are not:
+    /// the bus transactions hold the thread.
+    pub async fn dispatch(&mut self, batch: MsgBatch) -> MsgBatch {
+        let mut rsp = MsgBatch::default();
+
+        let delay = self.delay;
+
+        for msg in batch.msgs {
+            let (transport, bus) = (msg.transport, msg.bus);
+            if let Some(backend) = self.backend(bus) {
+                backend.begin();
+            }
+            let mut seqs = Vec::with_capacity(msg.seqs.len());
+
+            for seq in msg.seqs {
+                if seq.operation == Operation::Ack as i32 {
+                    log::info!("Received Ack operation");
+                    continue;
+                }
+
+                let result = if bus == BUS_AUTH {
+                    self.authenticate(&seq)
+                } else if !self.authenticated() {
+                    Err(ErrorCode::Unauthenticated.err("authenticate first on the auth bus"))
+                } else if bus == BUS_SCRIPT {
+                    self.script(&seq).await
+                } else if bus == BUS_STREAM {
+                    self.subscribe(&seq)
+                } else {
+                    match self.backend(bus) {
+                        Some(backend) => execute(backend, &seq),
+                        None => {
+                            Err(ErrorCode::UnknownBus.err(format!("no backend for bus {}", bus)))
+                        }
+                    }
+                };
+                let mut seq_rsp = match result {
+                    Ok(data) => BusOps {
+                        operation: Operation::Ack as i32,
+                        address: seq.address,
+                        data,
+                        ..Default::default()
+                    },
+                    Err(e) => {
+                        log::error!(
+                            "Operation {} on 0x{:x} failed: {:#}",
+                            seq.operation,
+                            seq.address,
+                            e
+                        );
+                        nack(seq.address, &e)
+                    }
+                };
+
+                if let Some(delay_us) = seq.delay_us {
+                    let elapsed = delay
+                        .wait(std::time::Duration::from_micros(delay_us as u64))
+                        .await;
+                    log::trace!("delay_us: {}, waited {:?}", delay_us, elapsed);
+                    seq_rsp.delay_us = Some(elapsed.as_micros() as _);
+                }
+                seqs.push(seq_rsp);
+            }
+
+            if !seqs.is_empty() {
+                rsp.msgs.push(Msg {
+                    transport,
+                    bus,
+                    seqs,
+                });
+            }
+        }
+
+        rsp
+    }
+
+    /// Run the script called `name` and return its replies, as
+    /// [`dispatch`](Self::dispatch) would for the stored batch.
+    pub async fn run_script(&mut self, name: &str) -> anyhow::Result<MsgBatch> {
+        if self.running {
+            fail!(InvalidArgument, "scripts cannot run scripts");
+        }
+        let Some(script) = self.scripts_mut()?.load(name)? else {
+            fail!(InvalidArgument, "no script {:?}", name);
+        };
+        let batch = MsgBatch::decode(script.as_slice())
+            .map_err(|e| ErrorCode::Malformed.err(format!("script {:?}: {}", name, e)))?;
+
+        log::info!("Running script {:?}", name);
+        self.running = true;
+        let rsp = self.dispatch(batch).await;
+        self.running = false;
+        Ok(rsp)
+    }
+
+    fn scripts_mut(&mut self) -> anyhow::Result<&mut (dyn ScriptStore + 'd)> {
+        match self.scripts.as_deref_mut() {
+            Some(scripts) => Ok(scripts),
+            None => fail!(UnknownBus, "no script store"),
+        }
+    }
+
+    /// Run an operation on [`BUS_SCRIPT`].
+    async fn script(&mut self, seq: &BusOps) -> anyhow::Result<Option<Vec<u8>>> {
+        let data = seq.data.as_deref().unwrap_or_default();
+
+        match seq.operation {
+            OP_RUN => {
+                let name = script::name(data)?;
+                // Boxed, `run_script` dispatching in turn
+                let rsp = Box::pin(self.run_script(name)).await?;
+                Ok(Some(rsp.encode_to_vec()))
+            }
+            op if op == Operation::Read as i32 => {
+                let name = script::name(data)?;
+                match self.scripts_mut()?.load(name)? {
+                    Some(script) => Ok(Some(script)),
+                    None => fail!(InvalidArgument, "no script {:?}", name),
+                }
+            }
+            op if op == Operation::Write as i32 => {
+                let (name, script) = script::upload(data)?;
+                if script.is_empty() {
+                    self.scripts_mut()?.remove(name)?;
+                } else {
+                    if let Err(e) = MsgBatch::decode(script) {
+                        fail!(Malformed, "script {:?}: {}", name, e);
+                    }
+                    self.scripts_mut()?.store(name, script)?;
+                }
+                Ok(None)
+            }
+            op => fail!(UnknownOperation, "operation {} on scripts", op),
+        }
+    }
+
+    /// Run an operation on [`BUS_AUTH`].
+    fn authenticate(&mut self, seq: &BusOps) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(auth) = &mut self.auth else {
+            fail!(Unsupported, "no secret set, nothing to authenticate");
+        };
+
+        match seq.operation {
+            op if op == Operation::Read as i32 => Ok(Some(auth.challenge().to_vec())),
+            op if op == Operation::Write as i32 => {
+                auth.verify(seq.data.as_deref().unwrap_or_default())?;
+                Ok(None)
+            }
+            op => fail!(UnknownOperation, "operation {} on auth", op),
+        }
+    }
+
+    /// Run an operation on [`BUS_STREAM`].
+    fn subscribe(&mut self, seq: &BusOps) -> anyhow::Result<Option<Vec<u8>>> {
+        if seq.operation != OP_WATCH {
+            fail!(UnknownOperation, "operation {} on streams", seq.operation);
+        }
+        if seq.address & ADDRESS_MASK == fifo::OVERFLOW_ID {
+            fail!(
+                InvalidArgument,
+                "stream 0x{:x} is reserved",
+                fifo::OVERFLOW_ID
+            );
+        }
+        let data = seq.data.as_deref().unwrap_or_default();
+        let stream = match data {
+            [] => None,
+            data => Some(Stream::decode(
+                seq.address,
+                data,
+                std::time::Instant::now(),
+            )?),
+        };
+        if let Some(stream) = &stream {
+            if stream.batch.msgs.iter().any(|msg| msg.bus == BUS_STREAM) {
+                fail!(InvalidArgument, "streams cannot subscribe to streams");
+            }
+        }
+
+        self.streams
+            .retain(|s| s.id & ADDRESS_MASK != seq.address & ADDRESS_MASK);
+        self.streams.extend(stream);
+        Ok(None)
+    }
+
+    /// When the next periodic sample is due, [`sample`](Self::sample) should
+    /// be called then.
+    pub fn next_sample(&self) -> Option<std::time::Instant> {
+        self.streams.iter().filter_map(Stream::deadline).min()
+    }
+
+    /// Take the samples of the periodic streams due at `now`.
+    pub async fn sample(&mut self, now: std::time::Instant) -> MsgBatch {
+        let mut rsp = MsgBatch::default();
+        for i in 0..self.streams.len() {
+            if self.streams[i].poll(now) && self.authenticated() {
+                self.take_sample(i, &mut rsp).await;
+            }
+        }
+        rsp
+    }
+
+    async fn take_sample(&mut self, i: usize, rsp: &mut MsgBatch) {
+        let header = self.streams[i].sample(stream::now_us());
+        let batch = self.streams[i].batch.clone();
+        rsp.msgs.push(Msg {
+            transport: TransportType::WebSocket as i32,
+            bus: BUS_STREAM,
+            seqs: vec![header],
+        });
+        rsp.msgs.extend(self.dispatch(batch).await.msgs);
+    }
+
+    /// Split `rsp` into the frames to send, honouring the flush threshold.
+    /// Operations keep their order and their `Msg` grouping, a `Msg` cut by
+    /// the threshold continues at the start of the next frame.
+    pub fn frames(&self, rsp: MsgBatch) -> Vec<MsgBatch> {
+        if rsp.msgs.is_empty() {
+            return Vec::new();
+        }
+        let Some(threshold) = self.flush_threshold else {
+            return vec![rsp];
+        };
+
+        let mut frames = Vec::new();
+        let mut frame = MsgBatch::default();
+        let mut ops = 0;
+
+        for msg in rsp.msgs {
+            let mut open = false;
+            for seq in msg.seqs {
+                if ops == threshold {
+                    frames.push(std::mem::take(&mut frame));
+                    ops = 0;
+                    open = false;
+                }
+                if !open {
+                    frame.msgs.push(Msg {
+                        transport: msg.transport,
+                        bus: msg.bus,
+                        seqs: Vec::new(),
+                    });
+                    open = true;
+                }
+                if let Some(current) = frame.msgs.last_mut() {
+                    current.seqs.push(seq);
+                }
+                ops += 1;
+            }
+        }
+        if !frame.msgs.is_empty() {
+            frames.push(frame);
+        }
+
+        frames
+    }
+
+    /// The buses served, registered ones first, then [`BUS_SCRIPT`] if a
+    /// store is set, [`BUS_STREAM`] and [`BUS_AUTH`] if a secret is set.
+    pub fn buses(&self) -> Vec<i32> {
+        let mut buses: Vec<i32> = self.buses.iter().map(|(bus, _)| *bus).collect();
+        if self.scripts.is_some() {
+            buses.push(BUS_SCRIPT);
+        }
+        buses.push(BUS_STREAM);
+        if self.auth.is_some() {
+            buses.push(BUS_AUTH);
+        }
+        buses
+    }
+
+    /// First batch sent on each connection, so the host learns what the
+    /// device serves without keeping state across reconnections: one `Msg`
+    /// per bus, [`BUS_SCRIPT`] and [`BUS_STREAM`] included, each holding an
+    /// [`OP_HELLO`].
+    ///
+    /// The batch starts with [`BUS_DEVICE`], whose hello data is `key=value`
+    /// lines: `chip`, `fw` and `mac` when set with
+    /// [`with_device`](Self::with_device), `max_payload`, see
+    /// [`MAX_PAYLOAD`], and `max_ops`, the flush threshold if any. The hello
+    /// of each bus holds its [`Capabilities`], encoded.
+    ///
+    /// With a secret, this starts a new session: the host has to
+    /// authenticate again, answering the challenge in the data of the
+    /// [`BUS_AUTH`] hello instead.
+    pub fn hello(&mut self) -> MsgBatch {
+        let challenge = self.auth.as_mut().map(|auth| auth.start().to_vec());
+
+        let mut device = Vec::new();
+        if let Some(info) = &self.device {
+            device.push(("chip", info.chip.to_owned()));
+            device.push(("fw", info.firmware.to_owned()));
+            device.push(("mac", info.mac_string()));
+        }
+        device.push(("max_payload", MAX_PAYLOAD.to_string()));
+        if let Some(ops) = self.flush_threshold {
+            device.push(("max_ops", ops.to_string()));
+        }
+
+        let mut hellos = vec![(BUS_DEVICE, Some(capabilities::lines(&device)))];
+        for bus in self.buses() {
+            let data = match bus {
+                BUS_AUTH => challenge.clone(),
+                BUS_SCRIPT => Some(
+                    Capabilities::new([Operation::Read as i32, Operation::Write as i32, OP_RUN])
+                        .encode(),
+                ),
+                BUS_STREAM => Some(Capabilities::new([OP_WATCH]).encode()),
+                bus => self.capabilities(bus).map(|caps| caps.encode()),
+            };
+            hellos.push((bus, data));
+        }
+
+        MsgBatch {
+            msgs: hellos
+                .into_iter()
+                .map(|(bus, data)| Msg {
+                    transport: TransportType::WebSocket as i32,
+                    bus,
+                    seqs: vec![BusOps {
+                        operation: OP_HELLO,
+                        data,
+                        ..Default::default()
+                    }],
+                })
+                .collect(),
+        }
+    }
+
+    /// What the backend of `bus` serves, with the pins set with
+    /// [`with_pins`](Self::with_pins).
+    fn capabilities(&self, bus: i32) -> Option<Capabilities> {
+        let (_, backend) = self.buses.iter().find(|(b, _)| *b == bus)?;
+        let pins = self
+            .pins
+            .iter()
+            .filter(|(b, _, _)| *b == bus)
+            .map(|(_, role, pin)| (role.as_str(), *pin));
+        Some(backend.capabilities().with_pins(pins))
+    }
+
+    /// Collect the notifications raised by the backends since the last call,
+    /// as one `Msg` of [`OP_EVENT`]s per bus, followed by the samples of the
+    /// streams triggered by these GPIO edges, then by the samples the
+    /// backends read themselves, see [`BusBackend::samples`].
+    pub async fn events(&mut self) -> MsgBatch {
+        let mut rsp = MsgBatch::default();
+        let mut samples = Vec::new();
+
+        for (bus, backend) in &mut self.buses {
+            samples.extend(backend.samples().into_iter().map(|sample| (*bus, sample)));
+            let seqs: Vec<BusOps> = backend
+                .events()
+                .into_iter()
+                .map(|(address, data)| BusOps {
+                    operation: OP_EVENT,
+                    address,
+                    data: Some(data),
+                    ..Default::default()
+                })
+                .collect();
+            if !seqs.is_empty() {
+                rsp.msgs.push(Msg {
+                    transport: TransportType::WebSocket as i32,
+                    bus: *bus,
+                    seqs,
+                });
+            }
+        }
+
+        if !self.authenticated() {
+            return MsgBatch::default();
+        }
+
+        let edges: Vec<u32> = rsp
+            .msgs
+            .iter()
+            .filter(|msg| msg.bus == BUS_GPIO)
+            .flat_map(|msg| msg.seqs.iter().map(|seq| seq.address))
+            .collect();
+        for i in 0..self.streams.len() {
+            if let Trigger::Gpio(gpio) = self.streams[i].trigger {
+                if edges.contains(&gpio) {
+                    self.take_sample(i, &mut rsp).await;
+                }
+            }
+        }
+        for (bus, sample) in samples {
+            rsp.msgs.extend(sample.into_batch(bus).msgs);
+        }
+
+        rsp
+    }
+}
+
+/// Run a single operation, returning the data to `Ack` with.
+fn execute(backend: &mut dyn BusBackend, seq: &BusOps) -> anyhow::Result<Option<Vec<u8>>> {
+    let address = seq.address & ADDRESS_MASK;
+    let data = seq.data.as_deref().unwrap_or_default();
+
+    match seq.operation {
+        OP_CONFIG => {
+            backend.configure(address, data)?;
+            return Ok(None);
+        }
+        OP_WATCH => {
+            backend.watch(address, data)?;
+            return Ok(None);
+        }
+        OP_SELECT => {
+            backend.select(address, data)?;
+            return Ok(None);
+        }
+        _ => {}
+    }
+
+    let Ok(operation) = Operation::try_from(seq.operation) else {
+        fail!(UnknownOperation, "operation {}", seq.operation);
+    };
+
+    match operation {
+        Operation::Ack => Ok(None),
+        Operation::Read => {
+            let mut rx_buf = vec![0; data.len()];
+            let len = backend.read(address, &mut rx_buf)?;
+            rx_buf.truncate(len);
+            Ok(Some(rx_buf))
+        }
+        Operation::Write => {
+            backend.write(address, data)?;
+            Ok(None)
+        }
+        Operation::Transfer => {
+            let mut rx_buf = data.to_vec();
+            let len = backend.transfer(address, &mut rx_buf)?;
+            rx_buf.truncate(len);
+            Ok(Some(rx_buf))
+        }
+    }
+}
+
+fn nack(address: u32, e: &anyhow::Error) -> BusOps {
+    let mut data = vec![ErrorCode::of(e) as u8];
+    data.extend_from_slice(format!("{:#}", e).as_bytes());
+    BusOps {
+        operation: OP_NACK,
+        address,
+        data: Some(data),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockBus;
+    use super::script::MemoryStore;
+    use super::*;
+
+    const SPI: i32 = BusType::Spi as i32;
+
+    fn op(operation: impl Into<i32>, address: u32, data: &[u8]) -> BusOps {
+        BusOps {
+            operation: operation.into(),
+            address,
+            data: Some(data.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    fn batch(bus: i32, seqs: Vec<BusOps>) -> MsgBatch {
+        MsgBatch {
+            msgs: vec![Msg {
+                transport: TransportType::WebSocket as i32,
+                bus,
+                seqs,
+            }],
+        }
+    }
+
+    fn bridge() -> Bridge<'static> {
+        let mut bus = MockBus::new();
+        bus.regs[0x10..0x14].copy_from_slice(&[1, 2, 3, 4]);
+        Bridge::new().with_bus(BusType::Spi, bus)
+    }
+
+    /// The replies to a batch holding a single `Msg` on `bus`.
+    async fn replies(bridge: &mut Bridge<'_>, bus: i32, seqs: Vec<BusOps>) -> Vec<BusOps> {
+        let rsp = bridge.dispatch(batch(bus, seqs)).await;
+        assert_eq!(rsp.msgs.len(), 1);
+        assert_eq!(rsp.msgs[0].bus, bus);
+        rsp.msgs.into_iter().next().unwrap().seqs
+    }
+
+    fn error_code(seq: &BusOps) -> u8 {
+        assert_eq!(seq.operation, OP_NACK);
+        seq.data.as_deref().unwrap()[0]
+    }
+
+    #[tokio::test]
+    async fn acks_register_operations() {
+        let mut bridge = bridge();
+        let rsp = bridge
+            .dispatch(batch(
+                SPI,
+                vec![
+                    op(Operation::Read, 0x10, &[0; 4]),
+                    op(Operation::Write, 0x20, &[5, 6]),
+                    op(Operation::Transfer, 0x20, &[7, 8]),
+                    op(Operation::Read, 0x20, &[0; 2]),
+                ],
+            ))
+            .await;
+        let rsp: Vec<BusOps> = rsp.msgs.into_iter().flat_map(|msg| msg.seqs).collect();
+
+        assert!(rsp.iter().all(|seq| seq.operation == Operation::Ack as i32));
+        assert_eq!(rsp[0].data.as_deref(), Some(&[1, 2, 3, 4][..]));
+        assert_eq!(rsp[1].data, None);
+        assert_eq!(rsp[2].data.as_deref(), Some(&[5, 6][..]));
+        assert_eq!(rsp[3].data.as_deref(), Some(&[7, 8][..]));
+    }
+
+    #[tokio::test]
+    async fn host_acks_are_not_answered() {
+        let rsp = bridge()
+            .dispatch(batch(SPI, vec![op(Operation::Ack, 0, &[])]))
+            .await;
+        assert!(rsp.msgs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn nacks_unknown_bus_and_operation() {
+        let mut bridge = bridge();
+        let rsp = replies(&mut bridge, 0x42, vec![op(Operation::Read, 0, &[0])]).await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::UnknownBus as u8);
+
+        let rsp = replies(&mut bridge, SPI, vec![op(0x42, 0, &[])]).await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::UnknownOperation as u8);
+
+        let rsp = replies(&mut bridge, SPI, vec![op(OP_WATCH, 0, &[])]).await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::Unsupported as u8);
+    }
+
+    #[tokio::test]
+    async fn echoes_tag() {
+        let mut bridge = bridge();
+        let rsp = replies(
+            &mut bridge,
+            SPI,
+            vec![
+                op(Operation::Read, 0xbeef_0010, &[0]),
+                op(Operation::Read, 0x1234_0010, &[0]),
+                op(0x42, 0xcafe_0000, &[]),
+            ],
+        )
+        .await;
+
+        assert_eq!(rsp[0].address, 0xbeef_0010);
+        assert_eq!(tag(rsp[0].address), 0xbeef);
+        assert_eq!(rsp[0].data.as_deref(), Some(&[1][..]));
+        assert_eq!(rsp[1].address, 0x1234_0010);
+        assert_eq!(rsp[1].data.as_deref(), Some(&[1][..]));
+        assert_eq!(rsp[2].address, 0xcafe_0000);
+    }
+
+    #[tokio::test]
+    async fn reports_waited_delay() {
+        let mut bridge = bridge();
+        let mut seq = op(Operation::Write, 0x20, &[1]);
+        seq.delay_us = Some(3_000);
+        let start = std::time::Instant::now();
+        let rsp = replies(&mut bridge, SPI, vec![seq]).await;
+
+        let waited = rsp[0].delay_us.unwrap();
+        assert!(waited >= 3_000, "waited {} µs", waited);
+        assert!(start.elapsed().as_micros() >= 3_000);
+    }
+
+    #[tokio::test]
+    async fn nacks_malformed_subscriptions() {
+        let mut bridge = bridge();
+        let rsp = replies(
+            &mut bridge,
+            BUS_STREAM,
+            vec![
+                op(OP_WATCH, 1, &[0, 1]),
+                op(OP_WATCH, 2, &[0, 0x10, 0x27, 0, 0, 0xff, 0xff]),
+                op(OP_WATCH, fifo::OVERFLOW_ID, &[]),
+            ],
+        )
+        .await;
+
+        assert_eq!(error_code(&rsp[0]), ErrorCode::InvalidArgument as u8);
+        assert_eq!(error_code(&rsp[1]), ErrorCode::Malformed as u8);
+        assert_eq!(error_code(&rsp[2]), ErrorCode::InvalidArgument as u8);
+        assert_eq!(bridge.next_sample(), None);
+    }
+
+    /// A bus whose device pushes one sample on each call.
+    struct SamplingBus(MockBus);
+
+    impl BusBackend for SamplingBus {
+        fn read(&mut self, address: u32, buf: &mut [u8]) -> anyhow::Result<usize> {
+            self.0.read(address, buf)
+        }
+
+        fn write(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+            self.0.write(address, data)
+        }
+
+        fn transfer(&mut self, address: u32, data: &mut [u8]) -> anyhow::Result<usize> {
+            self.0.transfer(address, data)
+        }
+
+        fn samples(&mut self) -> Vec<Sample> {
+            vec![Sample {
+                id: 5,
+                address: 0x10,
+                timestamp_us: 1234,
+                skipped: 2,
+                data: vec![1, 2],
+            }]
+        }
+    }
+
+    #[tokio::test]
+    async fn events_carry_backend_samples() {
+        let mut bridge = Bridge::new().with_bus(BusType::Spi, SamplingBus(MockBus::new()));
+        let rsp = bridge.events().await;
+
+        assert_eq!(rsp.msgs.len(), 2);
+        assert_eq!(rsp.msgs[0].bus, BUS_STREAM);
+        assert_eq!(rsp.msgs[0].seqs, [stream::header(5, 1234, 2)]);
+        assert_eq!(rsp.msgs[1].bus, SPI);
+        assert_eq!(rsp.msgs[1].seqs[0].operation, Operation::Ack as i32);
+        assert_eq!(rsp.msgs[1].seqs[0].address, 0x10);
+        assert_eq!(rsp.msgs[1].seqs[0].data.as_deref(), Some(&[1, 2][..]));
+
+        let mut bridge = bridge.with_auth(&b"0123456789abcdef"[..]);
+        bridge.hello();
+        assert!(bridge.events().await.msgs.is_empty());
+    }
+
+    #[test]
+    fn frames_honour_threshold() {
+        let reads = |n| (0..n).map(|a| op(Operation::Read, a, &[0])).collect();
+        let mut rsp = batch(SPI, reads(3));
+        rsp.msgs.extend(batch(BUS_GPIO, reads(2)).msgs);
+
+        assert_eq!(bridge().frames(rsp.clone()), vec![rsp.clone()]);
+        assert!(bridge().frames(MsgBatch::default()).is_empty());
+
+        let frames = bridge().with_flush_threshold(2).frames(rsp);
+        let shape: Vec<Vec<(i32, usize)>> = frames
+            .iter()
+            .map(|frame| frame.msgs.iter().map(|m| (m.bus, m.seqs.len())).collect())
+            .collect();
+        assert_eq!(
+            shape,
+            vec![
+                vec![(SPI, 2)],
+                vec![(SPI, 1), (BUS_GPIO, 1)],
+                vec![(BUS_GPIO, 1)],
+            ]
+        );
+        let addresses: Vec<u32> = frames
+            .iter()
+            .flat_map(|frame| &frame.msgs)
+            .flat_map(|msg| msg.seqs.iter().map(|seq| seq.address))
+            .collect();
+        assert_eq!(addresses, [0, 1, 2, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn handle_nacks_malformed_frame() {
+        let frames = bridge().handle(&[0xff; 3]).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(
+            error_code(&frames[0].msgs[0].seqs[0]),
+            ErrorCode::Malformed as u8
+        );
+    }
+
+    #[tokio::test]
+    async fn gates_operations_until_authenticated() {
+        let secret = b"0123456789abcdef";
+        let mut bridge = bridge().with_auth(&secret[..]);
+        let read = || vec![op(Operation::Read, 0x10, &[0])];
+
+        let hello = bridge.hello();
+        let challenge = hello
+            .msgs
+            .iter()
+            .find(|msg| msg.bus == BUS_AUTH)
+            .and_then(|msg| msg.seqs[0].data.clone())
+            .unwrap();
+        assert_eq!(challenge.len(), auth::CHALLENGE_LEN);
+
+        let rsp = replies(&mut bridge, SPI, read()).await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::Unauthenticated as u8);
+
+        let wrong = auth::answer(b"not the secret..", &challenge);
+        let rsp = replies(&mut bridge, BUS_AUTH, vec![op(Operation::Write, 0, &wrong)]).await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::Unauthenticated as u8);
+
+        // The challenge was used up by the wrong answer
+        let right = auth::answer(secret, &challenge);
+        let rsp = replies(&mut bridge, BUS_AUTH, vec![op(Operation::Write, 0, &right)]).await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::InvalidArgument as u8);
+
+        let rsp = replies(&mut bridge, BUS_AUTH, vec![op(Operation::Read, 0, &[])]).await;
+        let challenge = rsp[0].data.clone().unwrap();
+        let right = auth::answer(secret, &challenge);
+        let rsp = replies(&mut bridge, BUS_AUTH, vec![op(Operation::Write, 0, &right)]).await;
+        assert_eq!(rsp[0].operation, Operation::Ack as i32);
+
+        let rsp = replies(&mut bridge, SPI, read()).await;
+        assert_eq!(rsp[0].data.as_deref(), Some(&[1][..]));
+
+        // A new connection starts unauthenticated
+        bridge.hello();
+        let rsp = replies(&mut bridge, SPI, read()).await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::Unauthenticated as u8);
+    }
+
+    #[tokio::test]
+    async fn stores_and_runs_scripts() {
+        let mut bridge = bridge().with_scripts(MemoryStore::default());
+        let script = batch(
+            SPI,
+            vec![
+                op(Operation::Write, 0x30, &[9]),
+                op(Operation::Read, 0x30, &[0]),
+            ],
+        )
+        .encode_to_vec();
+        let mut upload = vec![4];
+        upload.extend_from_slice(b"init");
+        upload.extend_from_slice(&script);
+
+        let rsp = replies(
+            &mut bridge,
+            BUS_SCRIPT,
+            vec![
+                op(Operation::Write, 0, &upload),
+                op(Operation::Read, 0, b"init"),
+                op(OP_RUN, 0, b"init"),
+                op(OP_RUN, 0, b"none"),
+            ],
+        )
+        .await;
+
+        assert_eq!(rsp[0].operation, Operation::Ack as i32);
+        assert_eq!(rsp[1].data.as_deref(), Some(script.as_slice()));
+        let result = MsgBatch::decode(rsp[2].data.as_deref().unwrap()).unwrap();
+        assert_eq!(result.msgs[0].seqs[1].data.as_deref(), Some(&[9][..]));
+        assert_eq!(error_code(&rsp[3]), ErrorCode::InvalidArgument as u8);
+
+        let rsp = replies(
+            &mut bridge,
+            BUS_SCRIPT,
+            vec![op(Operation::Write, 0, b"\x04init"), op(OP_RUN, 0, b"init")],
+        )
+        .await;
+        assert_eq!(rsp[0].operation, Operation::Ack as i32);
+        assert_eq!(error_code(&rsp[1]), ErrorCode::InvalidArgument as u8);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_scripts() {
+        let mut bridge = bridge();
+        let rsp = replies(&mut bridge, BUS_SCRIPT, vec![op(OP_RUN, 0, b"init")]).await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::UnknownBus as u8);
+
+        let mut bridge = bridge.with_scripts(MemoryStore::default());
+        let rsp = replies(
+            &mut bridge,
+            BUS_SCRIPT,
+            vec![
+                op(Operation::Write, 0, b"\x04init\xff"),
+                op(Operation::Write, 0, b"\x00"),
+            ],
+        )
+        .await;
+        assert_eq!(error_code(&rsp[0]), ErrorCode::Malformed as u8);
+        assert_eq!(error_code(&rsp[1]), ErrorCode::InvalidArgument as u8);
+    }
+}
@@ -0,0 +1,41 @@
+//! Scripts kept in flash, in their own NVS namespace.
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+use super::script::ScriptStore;
+
+const NAMESPACE: &str = "scripts";
+
+/// [`ScriptStore`] on the default NVS partition, surviving resets.
+pub struct NvsScriptStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NvsScriptStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+}
+
+impl ScriptStore for NvsScriptStore {
+    fn load(&mut self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(len) = self.nvs.blob_len(name)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len];
+        let script = self.nvs.get_blob(name, &mut buf)?;
+        Ok(script.map(<[u8]>::to_vec))
+    }
+
+    fn store(&mut self, name: &str, script: &[u8]) -> anyhow::Result<()> {
+        self.nvs.set_blob(name, script)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> anyhow::Result<()> {
+        self.nvs.remove(name)?;
+        Ok(())
+    }
+}
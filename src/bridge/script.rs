@@ -0,0 +1,74 @@
+//! Named `MsgBatch` scripts kept on the device, see [`BUS_SCRIPT`](super::BUS_SCRIPT).
+
+use std::collections::HashMap;
+
+/// Longest script name, the key length limit of NVS.
+pub const MAX_NAME_LEN: usize = 15;
+
+/// Where scripts are kept, by name, as encoded `MsgBatch`es.
+pub trait ScriptStore {
+    /// The script called `name`, if any.
+    fn load(&mut self, name: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Save `script` as `name`, replacing any previous script of that name.
+    fn store(&mut self, name: &str, script: &[u8]) -> anyhow::Result<()>;
+
+    /// Delete the script called `name`, if any.
+    fn remove(&mut self, name: &str) -> anyhow::Result<()>;
+}
+
+/// Scripts kept in RAM, lost on reset.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    scripts: HashMap<String, Vec<u8>>,
+}
+
+impl ScriptStore for MemoryStore {
+    fn load(&mut self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.scripts.get(name).cloned())
+    }
+
+    fn store(&mut self, name: &str, script: &[u8]) -> anyhow::Result<()> {
+        self.scripts.insert(name.to_owned(), script.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> anyhow::Result<()> {
+        self.scripts.remove(name);
+        Ok(())
+    }
+}
+
+/// Check that `data` is a valid script name.
+pub fn name(data: &[u8]) -> anyhow::Result<&str> {
+    let Ok(name) = core::str::from_utf8(data) else {
+        fail!(InvalidArgument, "script name is not UTF-8");
+    };
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        fail!(
+            InvalidArgument,
+            "script name must be 1 to {} bytes, got {}",
+            MAX_NAME_LEN,
+            name.len()
+        );
+    }
+    Ok(name)
+}
+
+/// Split an upload, `[name_len, name, script]`, into the script name and the
+/// encoded `MsgBatch`.
+pub fn upload(data: &[u8]) -> anyhow::Result<(&str, &[u8])> {
+    let Some((&len, rest)) = data.split_first() else {
+        fail!(InvalidArgument, "empty script upload");
+    };
+    if rest.len() < len as usize {
+        fail!(
+            InvalidArgument,
+            "script name of {} bytes, {} left",
+            len,
+            rest.len()
+        );
+    }
+    let (name_bytes, script) = rest.split_at(len as usize);
+    Ok((name(name_bytes)?, script))
+}
@@ -0,0 +1,124 @@
+//! SPI bus settings the host can change at runtime.
+
+/// Fastest SPI clock, the APB clock the peripheral divides.
+pub const MAX_BAUDRATE: u32 = 80_000_000;
+
+/// Clock, mode, bit order and chip-select polarity of an SPI device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpiSettings {
+    pub baudrate: u32,
+    /// SPI mode `0` to `3`, CPOL in bit 1 and CPHA in bit 0.
+    pub mode: u8,
+    pub lsb_first: bool,
+    pub cs_active_high: bool,
+}
+
+impl SpiSettings {
+    /// Decode the settings sent by the host:
+    /// `[baudrate, mode, bit_order, cs_polarity]` with the baudrate in Hz as
+    /// 32-bit little endian, up to [`MAX_BAUDRATE`], bit order `0` MSB first or `1` LSB first and
+    /// chip-select polarity `0` active low or `1` active high.
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let [b0, b1, b2, b3, mode, bit_order, cs_polarity] = *data else {
+            fail!(
+                InvalidArgument,
+                "SPI settings need 7 bytes, got {}",
+                data.len()
+            );
+        };
+        let baudrate = u32::from_le_bytes([b0, b1, b2, b3]);
+        if baudrate == 0 || baudrate > MAX_BAUDRATE {
+            fail!(InvalidArgument, "baudrate {} Hz", baudrate);
+        }
+        if mode > 3 {
+            fail!(InvalidArgument, "SPI mode {}", mode);
+        }
+
+        Ok(Self {
+            baudrate,
+            mode,
+            lsb_first: match bit_order {
+                0 => false,
+                1 => true,
+                _ => fail!(InvalidArgument, "bit order {}", bit_order),
+            },
+            cs_active_high: match cs_polarity {
+                0 => false,
+                1 => true,
+                _ => fail!(InvalidArgument, "chip-select polarity {}", cs_polarity),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::ErrorCode;
+
+    fn settings(baudrate: u32, mode: u8, bit_order: u8, cs_polarity: u8) -> Vec<u8> {
+        let mut data = baudrate.to_le_bytes().to_vec();
+        data.extend_from_slice(&[mode, bit_order, cs_polarity]);
+        data
+    }
+
+    fn rejected(data: &[u8]) -> ErrorCode {
+        ErrorCode::of(&SpiSettings::decode(data).unwrap_err())
+    }
+
+    #[test]
+    fn decodes_settings() {
+        assert_eq!(
+            SpiSettings::decode(&settings(8_000_000, 3, 1, 1)).unwrap(),
+            SpiSettings {
+                baudrate: 8_000_000,
+                mode: 3,
+                lsb_first: true,
+                cs_active_high: true,
+            }
+        );
+        let decoded = SpiSettings::decode(&settings(1_000_000, 0, 0, 0)).unwrap();
+        assert!(!decoded.lsb_first);
+        assert!(!decoded.cs_active_high);
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let data = settings(8_000_000, 0, 0, 0);
+        assert_eq!(rejected(&[]), ErrorCode::InvalidArgument);
+        assert_eq!(rejected(&data[..6]), ErrorCode::InvalidArgument);
+        assert_eq!(
+            rejected(&[&data[..], &[0]].concat()),
+            ErrorCode::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_mode_and_bit_order() {
+        assert_eq!(
+            rejected(&settings(8_000_000, 4, 0, 0)),
+            ErrorCode::InvalidArgument
+        );
+        assert_eq!(
+            rejected(&settings(8_000_000, 0, 2, 0)),
+            ErrorCode::InvalidArgument
+        );
+        assert_eq!(
+            rejected(&settings(8_000_000, 0, 0, 2)),
+            ErrorCode::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn bounds_the_clock() {
+        assert_eq!(rejected(&settings(0, 0, 0, 0)), ErrorCode::InvalidArgument);
+        assert_eq!(
+            rejected(&settings(MAX_BAUDRATE + 1, 0, 0, 0)),
+            ErrorCode::InvalidArgument
+        );
+        for baudrate in [1, MAX_BAUDRATE] {
+            let decoded = SpiSettings::decode(&settings(baudrate, 0, 0, 0)).unwrap();
+            assert_eq!(decoded.baudrate, baudrate);
+        }
+    }
+}
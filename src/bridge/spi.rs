@@ -0,0 +1,312 @@
+//! SPI backend on top of `SpiDeviceDriver`.
+
+use core::borrow::Borrow;
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyOutputPin, InterruptType};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::spi::{config, SpiDeviceDriver, SpiDriver};
+use esp_idf_svc::hal::units::Hertz;
+
+use super::drdy::{Burst, Drdy, DrdyWatcher};
+use super::framing::Framing;
+use super::settings::SpiSettings;
+use super::stream::Sample;
+use super::{BusBackend, Capabilities, OP_CONFIG, OP_SELECT, OP_WATCH};
+
+/// [`OP_CONFIG`](super::OP_CONFIG) address replacing the [`Framing`] of the
+/// selected device, the data is decoded with [`Framing::decode`].
+pub const CONFIG_FRAMING: u32 = 0;
+
+/// [`OP_CONFIG`](super::OP_CONFIG) address changing the clock, mode, bit
+/// order and chip-select polarity of the selected device, the data is
+/// decoded with [`SpiSettings::decode`].
+pub const CONFIG_SETTINGS: u32 = 1;
+
+/// One chip-select on the bus.
+///
+/// The CS pin and the configuration are kept so the `SpiDeviceDriver` can be
+/// re-created when the host changes the [`SpiSettings`].
+struct Device<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + Clone,
+{
+    name: String,
+    cs: Option<AnyOutputPin>,
+    config: config::Config,
+    driver: Option<SpiDeviceDriver<'d, T>>,
+    framing: Framing,
+}
+
+impl<'d, T> Device<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + Clone,
+{
+    fn open(&mut self, bus: &T, config: &config::Config) -> anyhow::Result<SpiDeviceDriver<'d, T>> {
+        // SAFETY: the previous driver using the pin has been dropped
+        let cs = self.cs.as_mut().map(|cs| unsafe { cs.clone_unchecked() });
+        Ok(SpiDeviceDriver::new(bus.clone(), cs, config)?)
+    }
+
+    /// Re-create the driver with `settings`, going back to the previous
+    /// configuration if they are rejected.
+    fn apply(&mut self, bus: &T, settings: SpiSettings) -> anyhow::Result<()> {
+        let mut config = self.config.clone();
+        config.baudrate = Hertz(settings.baudrate);
+        config.data_mode = match settings.mode {
+            0 => config::MODE_0,
+            1 => config::MODE_1,
+            2 => config::MODE_2,
+            _ => config::MODE_3,
+        };
+        config.bit_order = if settings.lsb_first {
+            config::BitOrder::LsbFirst
+        } else {
+            config::BitOrder::MsbFirst
+        };
+        config.cs_active_high = settings.cs_active_high;
+
+        self.driver = None;
+        match self.open(bus, &config) {
+            Ok(driver) => {
+                self.driver = Some(driver);
+                self.config = config;
+                log::info!("SPI device {} reconfigured: {:?}", self.name, settings);
+                Ok(())
+            }
+            Err(e) => {
+                let previous = self.config.clone();
+                self.driver = self.open(bus, &previous).ok();
+                Err(e)
+            }
+        }
+    }
+
+    /// Clock `header` followed by `data`, returning what was received during
+    /// `data`.
+    fn exchange(&mut self, header: Vec<u8>, data: &mut [u8]) -> anyhow::Result<usize> {
+        let mut buf = header;
+        let start = buf.len();
+        buf.extend_from_slice(data);
+        self.driver()?.transfer_in_place(&mut buf)?;
+        data.copy_from_slice(&buf[start..]);
+        Ok(data.len())
+    }
+
+    fn driver(&mut self) -> anyhow::Result<&mut SpiDeviceDriver<'d, T>> {
+        let name = &self.name;
+        self.driver
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("SPI device {} lost in a failed reconfiguration", name))
+    }
+}
+
+/// The devices sharing one SPI controller, each behind its own chip-select.
+///
+/// Register accesses are framed by the [`Framing`] profile of the selected
+/// device, the IMU convention of an 8-bit address with bit 7 set for reads
+/// unless told otherwise. Each `Msg` starts on the first device, an
+/// [`OP_SELECT`](super::OP_SELECT) with the device index as address, or its
+/// name as data, switches to another one for the rest of the `Msg`.
+///
+/// An [`OP_WATCH`](super::OP_WATCH) arms a data-ready pin given with
+/// [`with_drdy`](Self::with_drdy): the data is `[gpio, trigger, len]` with
+/// trigger `0` off, `1` rising edge, `2` falling edge, `3` high level, `4`
+/// low level and `len` 16-bit little endian. On each trigger, `len` bytes at
+/// the address of the watch are read from the selected device and sent as a
+/// sample of stream id `gpio` on [`BUS_STREAM`](super::BUS_STREAM), see
+/// [`Sample`].
+pub struct SpiBus<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + Clone,
+{
+    bus: T,
+    devices: Vec<Device<'d, T>>,
+    selected: usize,
+    drdy: Vec<Drdy>,
+    watcher: DrdyWatcher,
+}
+
+impl<'d, T> SpiBus<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + Clone,
+{
+    pub fn new(bus: T) -> Self {
+        Self {
+            bus,
+            devices: Vec::new(),
+            selected: 0,
+            drdy: Vec::new(),
+            watcher: DrdyWatcher::new(),
+        }
+    }
+
+    /// Let the host arm `pin` as the data-ready pin of a device.
+    pub fn with_drdy(mut self, pin: AnyIOPin) -> anyhow::Result<Self> {
+        self.drdy.push(Drdy::new(pin, &self.watcher)?);
+        Ok(self)
+    }
+
+    pub fn drdy_watcher(&self) -> DrdyWatcher {
+        self.watcher.clone()
+    }
+
+    /// Add a device behind `cs`, its index is the number of devices added
+    /// before it.
+    pub fn with_device(
+        self,
+        name: &str,
+        cs: Option<AnyOutputPin>,
+        config: &config::Config,
+    ) -> anyhow::Result<Self> {
+        self.with_framed_device(name, cs, config, Framing::new())
+    }
+
+    /// [`with_device`](Self::with_device) for a device that does not follow
+    /// the default [`Framing`].
+    pub fn with_framed_device(
+        mut self,
+        name: &str,
+        cs: Option<AnyOutputPin>,
+        config: &config::Config,
+        framing: Framing,
+    ) -> anyhow::Result<Self> {
+        let mut device = Device {
+            name: name.to_string(),
+            cs,
+            config: config.clone(),
+            driver: None,
+            framing,
+        };
+        device.driver = Some(device.open(&self.bus, config)?);
+        self.devices.push(device);
+        Ok(self)
+    }
+
+    fn device(&mut self) -> anyhow::Result<&mut Device<'d, T>> {
+        match self.devices.get_mut(self.selected) {
+            Some(device) => Ok(device),
+            None => fail!(InvalidArgument, "no SPI device {}", self.selected),
+        }
+    }
+}
+
+impl<'d, T> BusBackend for SpiBus<'d, T>
+where
+    T: Borrow<SpiDriver<'d>> + Clone,
+{
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let device = self.device()?;
+        let header = device.framing.read_header(address, buf.len())?;
+        buf.fill(0);
+        device.exchange(header, buf)
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let device = self.device()?;
+        let mut tx_buf = device.framing.write_header(address, data.len())?;
+        tx_buf.extend_from_slice(data);
+        device.driver()?.write(&tx_buf)?;
+        Ok(())
+    }
+
+    fn transfer(&mut self, address: u32, data: &mut [u8]) -> anyhow::Result<usize> {
+        let device = self.device()?;
+        let header = device.framing.write_header(address, data.len())?;
+        device.exchange(header, data)
+    }
+
+    fn configure(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let bus = self.bus.clone();
+        let device = self.device()?;
+        match address {
+            CONFIG_FRAMING => device.framing = Framing::decode(data)?,
+            CONFIG_SETTINGS => device.apply(&bus, SpiSettings::decode(data)?)?,
+            _ => fail!(InvalidArgument, "unknown SPI configuration 0x{:x}", address),
+        }
+        Ok(())
+    }
+
+    fn watch(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let [gpio, trigger, l0, l1] = *data else {
+            fail!(
+                InvalidArgument,
+                "expected [gpio, trigger, len], got {} bytes",
+                data.len()
+            );
+        };
+        let trigger = match trigger {
+            0 => None,
+            1 => Some(InterruptType::PosEdge),
+            2 => Some(InterruptType::NegEdge),
+            3 => Some(InterruptType::HighLevel),
+            4 => Some(InterruptType::LowLevel),
+            _ => fail!(InvalidArgument, "invalid trigger {}", trigger),
+        };
+        let burst = Burst {
+            device: self.selected,
+            address,
+            len: u16::from_le_bytes([l0, l1]) as usize,
+        };
+        if trigger.is_some() {
+            if burst.len == 0 {
+                fail!(InvalidArgument, "empty DRDY read");
+            }
+            // Checks the address against the framing of the device
+            self.device()?.framing.read_header(address, burst.len)?;
+        }
+        let Some(drdy) = self.drdy.iter_mut().find(|drdy| drdy.gpio() == gpio as u32) else {
+            fail!(InvalidArgument, "GPIO{} is not a DRDY pin", gpio);
+        };
+        drdy.arm(trigger, burst)
+    }
+
+    fn begin(&mut self) {
+        self.selected = 0;
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut operations = vec![OP_CONFIG, OP_SELECT];
+        if !self.drdy.is_empty() {
+            operations.push(OP_WATCH);
+        }
+        Capabilities::registers()
+            .with_operations(operations)
+            .with_devices(self.devices.iter().map(|device| device.name.as_str()))
+            .with_pins(self.drdy.iter().map(|drdy| ("drdy", drdy.gpio())))
+    }
+
+    fn samples(&mut self) -> Vec<Sample> {
+        let devices = &mut self.devices;
+        self.drdy
+            .iter_mut()
+            .filter_map(|drdy| {
+                drdy.poll(|burst| {
+                    let Some(device) = devices.get_mut(burst.device) else {
+                        fail!(InvalidArgument, "no SPI device {}", burst.device);
+                    };
+                    let header = device.framing.read_header(burst.address, burst.len)?;
+                    let mut buf = vec![0; burst.len];
+                    device.exchange(header, &mut buf)?;
+                    Ok(buf)
+                })
+            })
+            .collect()
+    }
+
+    fn select(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let index = if data.is_empty() {
+            address as usize
+        } else {
+            let name = core::str::from_utf8(data)?;
+            match self.devices.iter().position(|device| device.name == name) {
+                Some(index) => index,
+                None => fail!(InvalidArgument, "no SPI device named {}", name),
+            }
+        };
+        if index >= self.devices.len() {
+            fail!(InvalidArgument, "no SPI device {}", index);
+        }
+        self.selected = index;
+        Ok(())
+    }
+}
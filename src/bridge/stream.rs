@@ -0,0 +1,281 @@
+//! Read sequences repeated by the device, see [`BUS_STREAM`](super::BUS_STREAM).
+
+use std::time::{Duration, Instant};
+
+use peripheral_bridge::pb::{msg::*, prost::Message};
+
+use super::OP_EVENT;
+
+/// What makes a stream take a sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// Once per period.
+    Period(Duration),
+    /// On each edge reported by a watched GPIO, e.g. the data-ready pin of a
+    /// sensor.
+    Gpio(u32),
+}
+
+/// A subscription: the batch to run and when to run it.
+#[derive(Debug, Clone)]
+pub struct Stream {
+    /// `BusOps.address` of the subscription, tag included, echoed in each
+    /// sample.
+    pub id: u32,
+    pub trigger: Trigger,
+    pub batch: MsgBatch,
+    next: Instant,
+    skipped: u32,
+}
+
+impl Stream {
+    /// Decode a subscription sent by the host: `[trigger, argument, batch]`
+    /// with trigger `0` for a period, the argument being the period in µs,
+    /// or `1` for a GPIO edge, the argument being the GPIO number. The
+    /// argument is 32-bit little endian and `batch` an encoded `MsgBatch`.
+    pub fn decode(id: u32, data: &[u8], now: Instant) -> anyhow::Result<Self> {
+        let (header, batch) = data.split_at(data.len().min(5));
+        let [trigger, a0, a1, a2, a3] = *header else {
+            fail!(
+                InvalidArgument,
+                "subscription needs 5 bytes before the batch, got {}",
+                data.len()
+            );
+        };
+        let argument = u32::from_le_bytes([a0, a1, a2, a3]);
+        let trigger = match trigger {
+            0 if argument == 0 => fail!(InvalidArgument, "period 0"),
+            0 => Trigger::Period(Duration::from_micros(argument as u64)),
+            1 => Trigger::Gpio(argument),
+            _ => fail!(InvalidArgument, "trigger {}", trigger),
+        };
+        let batch = match MsgBatch::decode(batch) {
+            Ok(batch) if !batch.msgs.is_empty() => batch,
+            Ok(_) => fail!(InvalidArgument, "empty subscription"),
+            Err(e) => fail!(Malformed, "subscription: {}", e),
+        };
+
+        let mut stream = Self {
+            id,
+            trigger,
+            batch,
+            next: now,
+            skipped: 0,
+        };
+        if let Trigger::Period(period) = trigger {
+            stream.next = now + period;
+        }
+        Ok(stream)
+    }
+
+    /// When the next sample of a periodic stream is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.trigger {
+            Trigger::Period(_) => Some(self.next),
+            Trigger::Gpio(_) => None,
+        }
+    }
+
+    /// Whether a periodic sample is due at `now`. If so the next one is
+    /// scheduled, the periods that went by without a sample being counted as
+    /// skipped.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let Trigger::Period(period) = self.trigger else {
+            return false;
+        };
+        if now < self.next {
+            return false;
+        }
+
+        let missed = ((now - self.next).as_nanos() / period.as_nanos()) as u32;
+        self.skipped = self.skipped.saturating_add(missed);
+        self.next += period * (missed + 1);
+        true
+    }
+
+    /// [`header`] of a sample taken at `timestamp_us`.
+    pub fn sample(&mut self, timestamp_us: u64) -> BusOps {
+        header(self.id, timestamp_us, std::mem::take(&mut self.skipped))
+    }
+}
+
+/// Header of a sample of stream `id` taken at `timestamp_us`: an
+/// [`OP_EVENT`] holding the timestamp as 64-bit little endian, then the
+/// samples skipped since the previous one as 32-bit little endian.
+pub fn header(id: u32, timestamp_us: u64, skipped: u32) -> BusOps {
+    let mut data = timestamp_us.to_le_bytes().to_vec();
+    data.extend_from_slice(&skipped.to_le_bytes());
+    BusOps {
+        operation: OP_EVENT,
+        address: id,
+        data: Some(data),
+        ..Default::default()
+    }
+}
+
+/// A sample a backend read by itself, e.g. on the data-ready pin of a
+/// sensor, see [`BusBackend::samples`](super::BusBackend::samples).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    /// Id of the stream in the [`header`].
+    pub id: u32,
+    /// Address of the read.
+    pub address: u32,
+    pub timestamp_us: u64,
+    /// Samples lost before this one.
+    pub skipped: u32,
+    pub data: Vec<u8>,
+}
+
+impl Sample {
+    /// The sample as [`BUS_STREAM`](super::BUS_STREAM) sends it: the
+    /// [`header`], then a `Msg` on `bus` holding the `Ack` of the read.
+    pub fn into_batch(self, bus: i32) -> MsgBatch {
+        MsgBatch {
+            msgs: vec![
+                Msg {
+                    transport: TransportType::WebSocket as i32,
+                    bus: super::BUS_STREAM,
+                    seqs: vec![header(self.id, self.timestamp_us, self.skipped)],
+                },
+                Msg {
+                    transport: TransportType::WebSocket as i32,
+                    bus,
+                    seqs: vec![BusOps {
+                        operation: Operation::Ack as i32,
+                        address: self.address,
+                        data: Some(self.data),
+                        ..Default::default()
+                    }],
+                },
+            ],
+        }
+    }
+}
+
+/// µs since boot, or since the first call off the ESP32.
+pub fn now_us() -> u64 {
+    #[cfg(target_os = "espidf")]
+    {
+        // SAFETY: reads the high resolution timer, callable from any task.
+        unsafe { esp_idf_svc::sys::esp_timer_get_time() as u64 }
+    }
+    #[cfg(not(target_os = "espidf"))]
+    {
+        static EPOCH: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+        EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::{ErrorCode, BUS_STREAM};
+
+    const PERIOD: Duration = Duration::from_millis(10);
+
+    fn batch() -> MsgBatch {
+        MsgBatch {
+            msgs: vec![Msg {
+                transport: TransportType::WebSocket as i32,
+                bus: BusType::Spi as i32,
+                seqs: vec![BusOps {
+                    operation: Operation::Read as i32,
+                    address: 0x28,
+                    data: Some(vec![0; 6]),
+                    ..Default::default()
+                }],
+            }],
+        }
+    }
+
+    /// A subscription of `batch` with `trigger` and `argument`.
+    fn spec(trigger: u8, argument: u32, batch: &[u8]) -> Vec<u8> {
+        let mut data = vec![trigger];
+        data.extend_from_slice(&argument.to_le_bytes());
+        data.extend_from_slice(batch);
+        data
+    }
+
+    fn rejected(data: &[u8]) -> ErrorCode {
+        ErrorCode::of(&Stream::decode(1, data, Instant::now()).unwrap_err())
+    }
+
+    #[test]
+    fn decodes_subscriptions() {
+        let now = Instant::now();
+        let encoded = batch().encode_to_vec();
+
+        let stream = Stream::decode(7, &spec(0, 10_000, &encoded), now).unwrap();
+        assert_eq!(stream.trigger, Trigger::Period(PERIOD));
+        assert_eq!(stream.deadline(), Some(now + PERIOD));
+        assert_eq!(stream.batch, batch());
+
+        let stream = Stream::decode(7, &spec(1, 18, &encoded), now).unwrap();
+        assert_eq!(stream.trigger, Trigger::Gpio(18));
+        assert_eq!(stream.deadline(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_subscriptions() {
+        let encoded = batch().encode_to_vec();
+        assert_eq!(rejected(&[0, 1, 0]), ErrorCode::InvalidArgument);
+        assert_eq!(rejected(&spec(0, 0, &encoded)), ErrorCode::InvalidArgument);
+        assert_eq!(rejected(&spec(2, 1, &encoded)), ErrorCode::InvalidArgument);
+        assert_eq!(rejected(&spec(0, 1_000, &[])), ErrorCode::InvalidArgument);
+        assert_eq!(rejected(&spec(0, 1_000, &[0xff; 3])), ErrorCode::Malformed);
+    }
+
+    #[test]
+    fn late_polls_count_skipped_periods() {
+        let start = Instant::now();
+        let mut stream =
+            Stream::decode(7, &spec(0, 10_000, &batch().encode_to_vec()), start).unwrap();
+
+        assert!(!stream.poll(start + PERIOD / 2));
+        assert!(stream.poll(start + PERIOD));
+        assert_eq!(stream.sample(0), header(7, 0, 0));
+
+        // Due at 2 periods, polled after 4.5: the 3rd and 4th were skipped
+        assert!(stream.poll(start + PERIOD * 9 / 2));
+        assert_eq!(stream.deadline(), Some(start + PERIOD * 5));
+        assert_eq!(stream.sample(45_000), header(7, 45_000, 2));
+        // Reported once
+        assert!(stream.poll(start + PERIOD * 5));
+        assert_eq!(stream.sample(50_000), header(7, 50_000, 0));
+    }
+
+    #[test]
+    fn gpio_streams_are_not_polled() {
+        let now = Instant::now();
+        let mut stream = Stream::decode(7, &spec(1, 18, &batch().encode_to_vec()), now).unwrap();
+        assert!(!stream.poll(now + PERIOD * 100));
+    }
+
+    #[test]
+    fn sample_batches_carry_tag_and_timestamp() {
+        let sample = Sample {
+            id: 0xbeef_0012,
+            address: 0xbeef_0028,
+            timestamp_us: 0x0102_0304_0506,
+            skipped: 3,
+            data: vec![1, 2],
+        };
+        let batch = sample.into_batch(BusType::Spi as i32);
+
+        assert_eq!(batch.msgs.len(), 2);
+        assert_eq!(batch.msgs[0].bus, BUS_STREAM);
+        let header = &batch.msgs[0].seqs[0];
+        assert_eq!(header.operation, OP_EVENT);
+        assert_eq!(header.address, 0xbeef_0012);
+        let data = header.data.as_deref().unwrap();
+        assert_eq!(data[..8], 0x0102_0304_0506u64.to_le_bytes());
+        assert_eq!(data[8..], 3u32.to_le_bytes());
+
+        assert_eq!(batch.msgs[1].bus, BusType::Spi as i32);
+        let ack = &batch.msgs[1].seqs[0];
+        assert_eq!(ack.operation, Operation::Ack as i32);
+        assert_eq!(ack.address, 0xbeef_0028);
+        assert_eq!(ack.data.as_deref(), Some(&[1, 2][..]));
+    }
+}
@@ -0,0 +1,57 @@
+//! UART passthrough backend on top of `UartDriver`.
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::uart::UartDriver;
+use esp_idf_svc::sys::TickType_t;
+
+use super::BusBackend;
+
+/// Raw byte stream to a module such as a GPS receiver or a modem.
+///
+/// There are no registers on a UART, so `BusOps.address` is reused as the
+/// read timeout in milliseconds; `0` selects the timeout given to [`UartBus::new`].
+/// A read returns whatever arrived before the timeout, which may be fewer
+/// bytes than requested.
+pub struct UartBus<'d> {
+    driver: UartDriver<'d>,
+    timeout_ms: u32,
+}
+
+impl<'d> UartBus<'d> {
+    pub fn new(driver: UartDriver<'d>, timeout_ms: u32) -> Self {
+        Self { driver, timeout_ms }
+    }
+
+    fn timeout(&self, address: u32) -> TickType_t {
+        let ms = if address == 0 {
+            self.timeout_ms
+        } else {
+            address
+        };
+        TickType::new_millis(ms as u64).ticks()
+    }
+}
+
+impl BusBackend for UartBus<'_> {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let len = self.driver.read(buf, self.timeout(address))?;
+        Ok(len)
+    }
+
+    fn write(&mut self, _address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let mut sent = 0;
+        while sent < data.len() {
+            sent += self.driver.write(&data[sent..])?;
+        }
+        Ok(())
+    }
+
+    /// Send `data` and collect the reply into the same buffer, dropping
+    /// anything received before the command went out.
+    fn transfer(&mut self, address: u32, data: &mut [u8]) -> anyhow::Result<usize> {
+        self.driver.clear_rx()?;
+        self.write(address, data)?;
+        let len = self.driver.read(data, self.timeout(address))?;
+        Ok(len)
+    }
+}
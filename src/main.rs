@@ -1,9 +1,11 @@
 fn main() {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    #[cfg(target_os = "espidf")]
     esp_idf_svc::sys::link_patches();
 
     // Bind the log crate to the ESP Logging facilities
+    #[cfg(target_os = "espidf")]
     esp_idf_svc::log::EspLogger::initialize_default();
 
     log::info!("Hello, world!");
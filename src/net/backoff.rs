@@ -0,0 +1,129 @@
+//! Delays between reconnection attempts.
+
+use std::time::Duration;
+
+/// Exponential backoff with jitter.
+///
+/// The delay doubles after each failed attempt, from `initial` up to `max`,
+/// and a random part of up to half of it is removed so boards that lost the
+/// same server do not all come back at once.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+    seed: u32,
+}
+
+impl Backoff {
+    /// `seed` feeds the jitter, e.g. `esp_random()`. A zero seed is replaced
+    /// as the generator would get stuck on it.
+    pub fn new(initial: Duration, max: Duration, seed: u32) -> Self {
+        Self {
+            initial,
+            max: max.max(initial),
+            attempt: 0,
+            seed: if seed == 0 { 0x9e37_79b9 } else { seed },
+        }
+    }
+
+    /// Start again from `initial`, after a successful connection.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Failed attempts since the last [`reset`](Self::reset).
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .initial
+            .checked_mul(1 << self.attempt.min(16))
+            .map_or(self.max, |delay| delay.min(self.max));
+        self.attempt = self.attempt.saturating_add(1);
+
+        let jitter = ceiling / 2;
+        ceiling - jitter.mul_f64(self.random() as f64 / u32::MAX as f64)
+    }
+
+    /// xorshift32, good enough to spread reconnections.
+    fn random(&mut self) -> u32 {
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INITIAL: Duration = Duration::from_millis(500);
+    const MAX: Duration = Duration::from_secs(30);
+
+    /// The delay is within the upper half of `ceiling`.
+    fn assert_jittered(delay: Duration, ceiling: Duration) {
+        assert!(delay <= ceiling, "{:?} over {:?}", delay, ceiling);
+        assert!(
+            delay >= ceiling / 2,
+            "{:?} under half of {:?}",
+            delay,
+            ceiling
+        );
+    }
+
+    #[test]
+    fn doubles_after_each_attempt() {
+        let mut backoff = Backoff::new(INITIAL, MAX, 1);
+        for attempt in 0..6 {
+            assert_eq!(backoff.attempt(), attempt);
+            assert_jittered(backoff.next_delay(), INITIAL * (1 << attempt));
+        }
+        assert_eq!(backoff.attempt(), 6);
+    }
+
+    #[test]
+    fn caps_at_max() {
+        let mut backoff = Backoff::new(INITIAL, MAX, 1);
+        for _ in 0..100 {
+            backoff.next_delay();
+        }
+        assert_jittered(backoff.next_delay(), MAX);
+
+        // A max under the initial delay is raised to it
+        let mut backoff = Backoff::new(MAX, INITIAL, 1);
+        assert_jittered(backoff.next_delay(), MAX);
+        assert_jittered(backoff.next_delay(), MAX);
+    }
+
+    #[test]
+    fn reset_starts_from_initial() {
+        let mut backoff = Backoff::new(INITIAL, MAX, 1);
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_jittered(backoff.next_delay(), INITIAL);
+    }
+
+    #[test]
+    fn jitter_spreads_delays() {
+        let mut a = Backoff::new(INITIAL, MAX, 1);
+        let mut b = Backoff::new(INITIAL, MAX, 2);
+        let delays =
+            |backoff: &mut Backoff| (0..8).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        assert_ne!(delays(&mut a), delays(&mut b));
+
+        // A zero seed still varies
+        let mut zero = Backoff::new(INITIAL, INITIAL, 0);
+        let first = zero.next_delay();
+        assert!((0..8).any(|_| zero.next_delay() != first));
+    }
+}
@@ -0,0 +1,203 @@
+//! Wi-Fi provisioning over BLE: a GATT service a phone writes the network
+//! and the server to, next to the NimBLE service of `examples/phyphox.rs`.
+
+use std::sync::{Arc, Mutex};
+
+use esp32_nimble::enums::{AuthReq, SecurityIOCap};
+use esp32_nimble::utilities::{mutex::Mutex as NimbleMutex, BleUuid};
+use esp32_nimble::{uuid128, BLEAdvertisementData, BLECharacteristic, BLEDevice, NimbleProperties};
+use tokio::sync::watch;
+
+use super::config::{validate_password, validate_url};
+use super::store::NetStore;
+use super::{Network, WifiState};
+
+const SERVICE: BleUuid = uuid128!("6e3b0001-5c1d-4a8e-9f2a-2d4b7c9e1f30");
+/// SSID of the network to join, UTF-8. Writing it saves the network, with
+/// the password written before.
+const SSID: BleUuid = uuid128!("6e3b0002-5c1d-4a8e-9f2a-2d4b7c9e1f30");
+/// Password of the network, write only, empty for an open network.
+const PASSWORD: BleUuid = uuid128!("6e3b0003-5c1d-4a8e-9f2a-2d4b7c9e1f30");
+/// `ws://` or `wss://` URL of the server, empty for none.
+const SERVER_URL: BleUuid = uuid128!("6e3b0004-5c1d-4a8e-9f2a-2d4b7c9e1f30");
+/// Outcome of the last write and state of the station, as text.
+const STATUS: BleUuid = uuid128!("6e3b0005-5c1d-4a8e-9f2a-2d4b7c9e1f30");
+
+/// Reads and writes need a link encrypted with a key paired by passkey.
+const READ: NimbleProperties = NimbleProperties::READ
+    .union(NimbleProperties::READ_ENC)
+    .union(NimbleProperties::READ_AUTHEN);
+const WRITE: NimbleProperties = NimbleProperties::WRITE
+    .union(NimbleProperties::WRITE_ENC)
+    .union(NimbleProperties::WRITE_AUTHEN);
+
+/// Largest passkey, 6 digits.
+pub const MAX_PASSKEY: u32 = 999_999;
+
+type Characteristic = Arc<NimbleMutex<BLECharacteristic>>;
+
+/// The provisioning service, only readable and writable by a phone bonded
+/// with the passkey. Invalid writes are rejected, valid ones saved to NVS
+/// at once, and new networks handed to the
+/// [`WifiManager`](super::wifi::WifiManager) so the station joins them
+/// without a reset. The server URL is used from the next boot.
+pub struct BleProvisioning {
+    status: Characteristic,
+}
+
+impl BleProvisioning {
+    /// Register the service and advertise it as `name`, pairing with
+    /// `passkey`. Networks saved are sent on `networks`.
+    pub fn start(
+        name: &str,
+        passkey: u32,
+        store: NetStore,
+        networks: watch::Sender<Vec<Network>>,
+    ) -> anyhow::Result<Self> {
+        if passkey > MAX_PASSKEY {
+            anyhow::bail!("BLE passkey must be at most 6 digits, got {}", passkey);
+        }
+        let config = store.load()?;
+        let store = Arc::new(Mutex::new(store));
+        let networks = Arc::new(networks);
+        let password = Arc::new(Mutex::new(String::new()));
+
+        let ble_device = BLEDevice::take();
+        // The phone enters the passkey when pairing, then stays bonded
+        ble_device
+            .security()
+            .set_auth(AuthReq::Bond | AuthReq::Mitm | AuthReq::Sc)
+            .set_passkey(passkey)
+            .set_io_cap(SecurityIOCap::DisplayOnly)
+            .resolve_rpa();
+        let server = ble_device.get_server();
+        server.on_connect(|_server, desc| {
+            log::info!("Provisioning client connected: {:?}", desc);
+        });
+        server.on_disconnect(|_desc, _reason| {
+            log::info!("Provisioning client disconnected");
+        });
+
+        let service = server.create_service(SERVICE);
+        let ssid = service.lock().create_characteristic(SSID, READ | WRITE);
+        let password_characteristic = service.lock().create_characteristic(PASSWORD, WRITE);
+        let server_url = service
+            .lock()
+            .create_characteristic(SERVER_URL, READ | WRITE);
+        let status = service
+            .lock()
+            .create_characteristic(STATUS, READ | NimbleProperties::NOTIFY);
+
+        let current = config.networks.first().map_or("", |n| n.ssid.as_str());
+        ssid.lock().set_value(current.as_bytes());
+        let url = config.server_url.as_deref().unwrap_or_default();
+        server_url.lock().set_value(url.as_bytes());
+        status
+            .lock()
+            .set_value(WifiState::Down.to_string().as_bytes());
+
+        {
+            let (password, status) = (password.clone(), status.clone());
+            password_characteristic.lock().on_write(move |args| {
+                let result = std::str::from_utf8(args.recv_data())
+                    .map_err(anyhow::Error::from)
+                    .and_then(|value| validate_password(value).map(|()| value.to_owned()));
+                match result {
+                    Ok(value) => *password.lock().unwrap() = value,
+                    Err(e) => {
+                        args.reject();
+                        set_status(&status, &format!("error: {:#}", e));
+                    }
+                }
+            });
+        }
+
+        {
+            let (store, status) = (store.clone(), status.clone());
+            ssid.lock().on_write(move |args| {
+                let result = std::str::from_utf8(args.recv_data())
+                    .map_err(anyhow::Error::from)
+                    .and_then(|ssid| {
+                        let network = Network::new(ssid, password.lock().unwrap().as_str());
+                        network.validate()?;
+                        let mut store = store.lock().unwrap();
+                        let config = store.load()?.with_network(network);
+                        store.save(&config)?;
+                        Ok(config)
+                    });
+                match result {
+                    Ok(config) => {
+                        set_status(&status, &format!("saved {}", config.networks[0].ssid));
+                        networks.send_replace(config.networks);
+                    }
+                    Err(e) => {
+                        args.reject();
+                        set_status(&status, &format!("error: {:#}", e));
+                    }
+                }
+            });
+        }
+
+        {
+            let status = status.clone();
+            server_url.lock().on_write(move |args| {
+                let result = std::str::from_utf8(args.recv_data())
+                    .map_err(anyhow::Error::from)
+                    .and_then(|url| {
+                        let url = match url.trim() {
+                            "" => None,
+                            url => {
+                                validate_url(url)?;
+                                Some(url.to_owned())
+                            }
+                        };
+                        let mut store = store.lock().unwrap();
+                        let mut config = store.load()?;
+                        config.server_url = url;
+                        store.save(&config)
+                    });
+                match result {
+                    Ok(()) => set_status(&status, "saved server URL, used after reset"),
+                    Err(e) => {
+                        args.reject();
+                        set_status(&status, &format!("error: {:#}", e));
+                    }
+                }
+            });
+        }
+
+        let ble_advertiser = ble_device.get_advertising();
+        ble_advertiser
+            .lock()
+            .set_data(
+                BLEAdvertisementData::new()
+                    .name(name)
+                    .add_service_uuid(SERVICE),
+            )
+            .map_err(|e| anyhow::anyhow!("BLE advertisement: {:?}", e))?;
+        ble_advertiser
+            .lock()
+            .start()
+            .map_err(|e| anyhow::anyhow!("BLE advertising: {:?}", e))?;
+        log::info!("Provisioning over BLE as {}", name);
+
+        Ok(Self { status })
+    }
+
+    /// Mirror `wifi` in the status characteristic, notifying each change,
+    /// until its sender is dropped.
+    pub async fn run(self, mut wifi: watch::Receiver<WifiState>) {
+        loop {
+            let state = wifi.borrow_and_update().to_string();
+            set_status(&self.status, &state);
+            if wifi.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn set_status(status: &Characteristic, text: &str) {
+    log::info!("Provisioning status: {}", text);
+    status.lock().set_value(text.as_bytes()).notify();
+}
@@ -0,0 +1,326 @@
+//! Protocol helpers of the provisioning portal, see `portal`: the DNS
+//! replies sending every name to the board, the form and the page.
+
+use std::fmt::Write as _;
+use std::net::Ipv4Addr;
+
+use super::config::NetConfig;
+use super::Network;
+
+/// Answer a DNS query with `ip` for whatever name it asks, so the phone or
+/// laptop joining the access point opens the portal. Returns `None` for
+/// packets that are not queries.
+pub fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < 12 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    // Only standard queries
+    if flags & 0xf800 != 0 || questions == 0 {
+        return None;
+    }
+
+    // Name of the first question, then its type and class
+    let mut end = 12;
+    loop {
+        let len = *query.get(end)? as usize;
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    end += 4;
+    if end > query.len() {
+        return None;
+    }
+    // A or ANY
+    let answer = qtype == 1 || qtype == 255;
+
+    let mut reply = Vec::with_capacity(end + 16);
+    reply.extend_from_slice(&query[..2]);
+    // Response, authoritative, recursion desired copied
+    reply.extend_from_slice(&(0x8400 | (flags & 0x0100)).to_be_bytes());
+    reply.extend_from_slice(&1u16.to_be_bytes());
+    reply.extend_from_slice(&(answer as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(&query[12..end]);
+    if answer {
+        // Pointer to the name of the question, A, IN, 60 s TTL
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+/// Decode an `application/x-www-form-urlencoded` body.
+pub fn parse_form(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+fn decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(b) => {
+                        bytes.push(b);
+                        rest = &rest[2..];
+                    }
+                    None => bytes.push(b'%'),
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// `config` with the network and server URL submitted in `form` in front,
+/// validated. The other networks are kept, after the new one.
+pub fn apply_form(config: &NetConfig, form: &[(String, String)]) -> anyhow::Result<NetConfig> {
+    let field = |name: &str| {
+        form.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.trim())
+            .unwrap_or_default()
+    };
+
+    let network = Network::new(field("ssid"), field("password"));
+    network.validate()?;
+    let mut config = config.clone().with_network(network);
+    config.server_url = match field("server_url") {
+        "" => None,
+        url => Some(url.to_owned()),
+    };
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// The provisioning page: the networks seen by the last scan, as
+/// `(ssid, rssi)`, and the current configuration, `message` being shown
+/// above the form.
+pub fn page(scan: &[(String, i8)], config: &NetConfig, message: &str) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\">\
+         <title>ESP32 bridge</title></head><body><h1>ESP32 bridge</h1>",
+    );
+    if !message.is_empty() {
+        let _ = write!(html, "<p><b>{}</b></p>", escape(message));
+    }
+    let ssid = config.networks.first().map_or("", |n| n.ssid.as_str());
+    let url = config.server_url.as_deref().unwrap_or_default();
+    let _ = write!(
+        html,
+        "<form method=\"post\" action=\"/save\">\
+         <p><label>Network<br><input name=\"ssid\" list=\"ssids\" value=\"{}\" required></label></p>\
+         <datalist id=\"ssids\">",
+        escape(ssid)
+    );
+    for (ssid, rssi) in scan {
+        let _ = write!(
+            html,
+            "<option value=\"{0}\">{0} ({1} dBm)</option>",
+            escape(ssid),
+            rssi
+        );
+    }
+    let _ = write!(
+        html,
+        "</datalist>\
+         <p><label>Password<br><input name=\"password\" type=\"password\"></label></p>\
+         <p><label>Server URL<br><input name=\"server_url\" value=\"{}\" placeholder=\"ws://host:8765\"></label></p>\
+         <p><button type=\"submit\">Save and reboot</button></p></form></body></html>",
+        escape(url)
+    );
+    html
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    /// A standard query of id 0x1234, recursion desired, for `labels` of
+    /// type `qtype`.
+    fn query(labels: &[&str], qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in labels {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&1u16.to_be_bytes());
+        query
+    }
+
+    fn form(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn answers_any_name_with_the_board() {
+        let query = query(&["connectivitycheck", "gstatic", "com"], 1);
+        let reply = dns_reply(&query, IP).unwrap();
+        // Same id, response with recursion desired, one question and answer
+        assert_eq!(
+            reply[..12],
+            [0x12, 0x34, 0x85, 0x00, 0, 1, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(reply[12..query.len()], query[12..]);
+        assert_eq!(
+            reply[query.len()..],
+            [0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 71, 1]
+        );
+    }
+
+    #[test]
+    fn answers_other_types_without_records() {
+        // AAAA
+        let query = query(&["example", "com"], 28);
+        let reply = dns_reply(&query, IP).unwrap();
+        assert_eq!(reply[6..8], [0, 0]);
+        assert_eq!(reply.len(), query.len());
+    }
+
+    #[test]
+    fn ignores_truncated_queries() {
+        let query = query(&["example", "com"], 1);
+        assert_eq!(dns_reply(&query[..11], IP), None);
+        // In the name, then in its type and class
+        assert_eq!(dns_reply(&query[..16], IP), None);
+        assert_eq!(dns_reply(&query[..query.len() - 1], IP), None);
+        assert_eq!(dns_reply(&query[..query.len() - 3], IP), None);
+    }
+
+    #[test]
+    fn ignores_compressed_names_and_non_queries() {
+        let mut compressed = query(&[], 1);
+        compressed.splice(12..13, [0xc0, 0x0c]);
+        assert_eq!(dns_reply(&compressed, IP), None);
+
+        let mut response = query(&["example", "com"], 1);
+        response[2] |= 0x80;
+        assert_eq!(dns_reply(&response, IP), None);
+
+        let mut no_question = query(&["example", "com"], 1);
+        no_question[5] = 0;
+        assert_eq!(dns_reply(&no_question, IP), None);
+    }
+
+    #[test]
+    fn decodes_forms() {
+        assert_eq!(
+            parse_form("ssid=My+Wi-Fi&password=p%40ss%3Dw0rd&&server_url"),
+            form(&[
+                ("ssid", "My Wi-Fi"),
+                ("password", "p@ss=w0rd"),
+                ("server_url", ""),
+            ])
+        );
+        assert_eq!(decode("caf%C3%A9"), "café");
+    }
+
+    #[test]
+    fn keeps_invalid_escapes() {
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%4"), "%4");
+        assert_eq!(decode("%zz%41"), "%zzA");
+        // Not splitting a multi-byte character
+        assert_eq!(decode("%é"), "%é");
+    }
+
+    #[test]
+    fn applies_forms_in_front_of_the_configuration() {
+        let config = NetConfig {
+            networks: vec![Network::new("home", "password1"), Network::new("lab", "")],
+            server_url: Some("ws://old:8765".to_owned()),
+            ..Default::default()
+        };
+        let new = apply_form(
+            &config,
+            &form(&[
+                ("ssid", " lab "),
+                ("password", "password2"),
+                ("server_url", "wss://host:8765/bridge"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            new.networks,
+            [
+                Network::new("lab", "password2"),
+                Network::new("home", "password1")
+            ]
+        );
+        assert_eq!(new.server_url.as_deref(), Some("wss://host:8765/bridge"));
+
+        let new = apply_form(&config, &form(&[("ssid", "lab")])).unwrap();
+        assert_eq!(new.server_url, None);
+    }
+
+    #[test]
+    fn rejects_invalid_forms() {
+        let config = NetConfig::default();
+        for pairs in [
+            &[("password", "password1")][..],
+            &[("ssid", "lab"), ("password", "short")],
+            &[("ssid", "lab"), ("server_url", "http://host")],
+        ] {
+            assert!(apply_form(&config, &form(pairs)).is_err(), "{:?}", pairs);
+        }
+    }
+
+    #[test]
+    fn escapes_ssids_in_the_page() {
+        let config = NetConfig {
+            networks: vec![Network::new("<b>\"home\"</b>", "")],
+            ..Default::default()
+        };
+        let html = page(&[("Tom & Jerry's".to_owned(), -40)], &config, "");
+        assert!(html.contains("value=\"&lt;b&gt;&quot;home&quot;&lt;/b&gt;\""));
+        assert!(html
+            .contains("<option value=\"Tom &amp; Jerry&#39;s\">Tom &amp; Jerry&#39;s (-40 dBm)"));
+        assert!(!html.contains("<b>\"home"));
+    }
+}
@@ -0,0 +1,172 @@
+//! Networks and server the firmware connects to.
+
+use super::Network;
+
+/// Networks kept in NVS, tried in this order.
+pub const MAX_NETWORKS: usize = 4;
+
+/// What the board connects to, loaded from NVS at boot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetConfig {
+    pub networks: Vec<Network>,
+    /// `ws://` or `wss://` URL of the host.
+    pub server_url: Option<String>,
+    pub tls: TlsConfig,
+    /// Secret the host proves it knows before it is served, see
+    /// [`BUS_AUTH`](crate::bridge::BUS_AUTH).
+    pub auth_secret: Option<String>,
+}
+
+/// Certificates of `wss://` connections, PEM encoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// Certificate of the server, pinned, or of the CA it must be signed by.
+    /// Without it, the CA bundle built into the firmware is used.
+    pub ca_cert: Option<String>,
+    /// Certificate and key presented to the server for mutual
+    /// authentication, both or neither.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl NetConfig {
+    /// Values given at build time, e.g.
+    /// `SSID=wifi_name PASSWD=xxx SERVER_URL=ws://host_ip:8765 cargo run`,
+    /// with an optional fallback network in `SSID2` and `PASSWD2`, and the
+    /// PEM certificates in `SERVER_CA`, `CLIENT_CERT` and `CLIENT_KEY`, and
+    /// the secret of the hosts in `BRIDGE_SECRET`. Only used for what NVS
+    /// does not hold.
+    pub fn build_defaults() -> Self {
+        let networks = [
+            (option_env!("SSID"), option_env!("PASSWD")),
+            (option_env!("SSID2"), option_env!("PASSWD2")),
+        ]
+        .into_iter()
+        .filter_map(|(ssid, passwd)| Some(Network::new(ssid?, passwd.unwrap_or_default())))
+        .filter(|network| !network.ssid.is_empty())
+        .collect();
+
+        Self {
+            networks,
+            server_url: option_env!("SERVER_URL").map(str::to_owned),
+            tls: TlsConfig {
+                ca_cert: option_env!("SERVER_CA").map(str::to_owned),
+                client_cert: option_env!("CLIENT_CERT").map(str::to_owned),
+                client_key: option_env!("CLIENT_KEY").map(str::to_owned),
+            },
+            auth_secret: option_env!("BRIDGE_SECRET").map(str::to_owned),
+        }
+    }
+
+    /// Put `network` first, before the other networks, replacing any network
+    /// of the same SSID.
+    #[must_use]
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.networks.retain(|n| n.ssid != network.ssid);
+        self.networks.insert(0, network);
+        self.networks.truncate(MAX_NETWORKS);
+        self
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.networks.len() > MAX_NETWORKS {
+            anyhow::bail!("At most {} networks", MAX_NETWORKS);
+        }
+        for network in &self.networks {
+            network.validate()?;
+        }
+        if let Some(url) = &self.server_url {
+            validate_url(url)?;
+        }
+        if let Some(secret) = &self.auth_secret {
+            validate_secret(secret)?;
+        }
+        self.tls.validate()
+    }
+}
+
+impl TlsConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, pem) in [
+            ("CA certificate", &self.ca_cert),
+            ("Client certificate", &self.client_cert),
+            ("Client key", &self.client_key),
+        ] {
+            if let Some(pem) = pem {
+                validate_pem(name, pem)?;
+            }
+        }
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            anyhow::bail!("Client certificate and key go together");
+        }
+        Ok(())
+    }
+}
+
+impl Network {
+    /// Check the SSID and password fit the Wi-Fi driver: an SSID of 1 to 32
+    /// bytes and no password or one of 8 to 64 bytes.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 {
+            anyhow::bail!("SSID must be 1 to 32 bytes, got {}", self.ssid.len());
+        }
+        validate_password(&self.password)
+    }
+}
+
+/// Check `password` is empty, for an open network, or 8 to 64 bytes.
+pub fn validate_password(password: &str) -> anyhow::Result<()> {
+    if !password.is_empty() && !(8..=64).contains(&password.len()) {
+        anyhow::bail!("Password must be 8 to 64 bytes, got {}", password.len());
+    }
+    Ok(())
+}
+
+/// Check `url` is a WebSocket URL with a host.
+pub fn validate_url(url: &str) -> anyhow::Result<()> {
+    let Some(rest) = url
+        .strip_prefix("ws://")
+        .or_else(|| url.strip_prefix("wss://"))
+    else {
+        anyhow::bail!("Server URL {:?} is not ws:// or wss://", url);
+    };
+    if rest.split(['/', ':']).next().unwrap_or_default().is_empty() {
+        anyhow::bail!("Server URL {:?} has no host", url);
+    }
+    Ok(())
+}
+
+/// Check `secret` is 16 to 64 bytes, long enough not to be guessed.
+pub fn validate_secret(secret: &str) -> anyhow::Result<()> {
+    if !(16..=64).contains(&secret.len()) {
+        anyhow::bail!("Secret must be 16 to 64 bytes, got {}", secret.len());
+    }
+    Ok(())
+}
+
+/// Check `pem` holds a PEM block, the `-----BEGIN` and `-----END` lines
+/// included.
+pub fn validate_pem(name: &str, pem: &str) -> anyhow::Result<()> {
+    let Some(begin) = pem.find("-----BEGIN ") else {
+        anyhow::bail!("{} is not PEM encoded", name);
+    };
+    if !pem[begin..].contains("-----END ") {
+        anyhow::bail!("{} is truncated", name);
+    }
+    Ok(())
+}
+
+/// Host and port `url` connects to, the port defaulting to 80 for `ws://`
+/// and 443 for `wss://`.
+pub fn endpoint(url: &str) -> anyhow::Result<(&str, u16)> {
+    validate_url(url)?;
+    let (scheme, rest) = url.split_once("://").unwrap_or_default();
+    let authority = rest.split('/').next().unwrap_or_default();
+    match authority.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => Ok((host, port)),
+            Err(_) => anyhow::bail!("Server URL {:?} has an invalid port", url),
+        },
+        None => Ok((authority, if scheme == "wss" { 443 } else { 80 })),
+    }
+}
@@ -0,0 +1,104 @@
+//! Frames of the raw TCP transport: each encoded `MsgBatch` follows its
+//! length, a little-endian `u32`.
+
+/// Longest frame accepted from a host.
+pub const MAX_LEN: usize = crate::bridge::MAX_PAYLOAD;
+
+const PREFIX_LEN: usize = 4;
+
+/// `payload` with its length prefix.
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(PREFIX_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Splits the bytes read from a socket into frames, however they were
+/// segmented on the way.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The payload of the next complete frame, if any. A length beyond
+    /// [`MAX_LEN`] is an error, the stream cannot be resynchronised.
+    pub fn decode(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(prefix) = self.buf.first_chunk::<PREFIX_LEN>() else {
+            return Ok(None);
+        };
+        let len = u32::from_le_bytes(*prefix) as usize;
+        if len > MAX_LEN {
+            anyhow::bail!("frame of {} bytes, at most {}", len, MAX_LEN);
+        }
+        if self.buf.len() < PREFIX_LEN + len {
+            return Ok(None);
+        }
+        let payload = self.buf[PREFIX_LEN..PREFIX_LEN + len].to_vec();
+        self.buf.drain(..PREFIX_LEN + len);
+        Ok(Some(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_the_length() {
+        assert_eq!(encode(b"abc"), [3, 0, 0, 0, b'a', b'b', b'c']);
+        assert_eq!(encode(b""), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn waits_for_a_split_frame() {
+        let frame = encode(b"hello");
+        let mut decoder = Decoder::new();
+        // Within the prefix, then within the payload
+        decoder.extend(&frame[..2]);
+        assert_eq!(decoder.decode().unwrap(), None);
+        decoder.extend(&frame[2..6]);
+        assert_eq!(decoder.decode().unwrap(), None);
+        decoder.extend(&frame[6..]);
+        assert_eq!(decoder.decode().unwrap().as_deref(), Some(&b"hello"[..]));
+        assert_eq!(decoder.decode().unwrap(), None);
+    }
+
+    #[test]
+    fn splits_frames_read_together() {
+        let mut data = encode(b"first");
+        data.extend(encode(b""));
+        data.extend(encode(b"third"));
+        // And the start of a fourth
+        data.extend(&encode(b"fourth")[..3]);
+        let mut decoder = Decoder::new();
+        decoder.extend(&data);
+        assert_eq!(decoder.decode().unwrap().as_deref(), Some(&b"first"[..]));
+        assert_eq!(decoder.decode().unwrap().as_deref(), Some(&b""[..]));
+        assert_eq!(decoder.decode().unwrap().as_deref(), Some(&b"third"[..]));
+        assert_eq!(decoder.decode().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_long_frames_from_their_prefix() {
+        let mut decoder = Decoder::new();
+        decoder.extend(&(MAX_LEN as u32).to_le_bytes());
+        assert_eq!(decoder.decode().unwrap(), None);
+
+        let mut decoder = Decoder::new();
+        decoder.extend(&(MAX_LEN as u32 + 1).to_le_bytes());
+        assert!(decoder.decode().is_err());
+        let mut decoder = Decoder::new();
+        decoder.extend(&u32::MAX.to_le_bytes());
+        assert!(decoder.decode().is_err());
+    }
+}
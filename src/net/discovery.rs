@@ -0,0 +1,121 @@
+//! What the board announces over mDNS, and servers found by their service
+//! type rather than their address, see [`mdns`](super::mdns).
+
+use std::net::SocketAddr;
+
+use crate::bridge::bus_name;
+use crate::bridge::device::DeviceInfo;
+
+/// The TXT records describing the board: `chip`, `fw`, `buses`, e.g.
+/// `spi,i2c,stream`, and `mac`.
+pub fn txt(device: &DeviceInfo, buses: &[i32]) -> Vec<(&'static str, String)> {
+    let buses: Vec<&str> = buses.iter().filter_map(|bus| bus_name(*bus)).collect();
+    vec![
+        ("chip", device.chip.to_owned()),
+        ("fw", device.firmware.to_owned()),
+        ("buses", buses.join(",")),
+        ("mac", device.mac_string()),
+    ]
+}
+
+/// `prefix` followed by the end of the MAC, so that boards on the same
+/// network answer to different names.
+pub fn hostname(prefix: &str, device: &DeviceInfo) -> String {
+    let [.., a, b, c] = device.mac;
+    format!("{}-{:02x}{:02x}{:02x}", prefix, a, b, c)
+}
+
+/// The service type `url` names instead of a host, as in
+/// `ws://_bridge-host._tcp.local/path`, or `None` for a plain host.
+pub fn service(url: &str) -> Option<&str> {
+    let (_, authority, _) = split(url)?;
+    let host = authority.split(':').next().unwrap_or_default();
+    let host = host.strip_suffix(".local").unwrap_or(host);
+    let service = host.strip_suffix("._tcp")?;
+    (service.starts_with('_') && service.len() > 1).then_some(service)
+}
+
+/// `url` pointing at `addr`, e.g. the address a [`service`] resolved to.
+pub fn with_address(url: &str, addr: SocketAddr) -> String {
+    match split(url) {
+        Some((scheme, _, path)) => format!("{}://{}{}", scheme, addr, path),
+        None => url.to_owned(),
+    }
+}
+
+/// The scheme, authority and path of `url`.
+fn split(url: &str) -> Option<(&str, &str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let end = rest.find('/').unwrap_or(rest.len());
+    Some((scheme, &rest[..end], &rest[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::{BUS_GPIO, BUS_STREAM};
+    use peripheral_bridge::pb::msg::BusType;
+
+    const DEVICE: DeviceInfo = DeviceInfo {
+        chip: "esp32c3",
+        firmware: "0.1.0",
+        mac: [0x34, 0x85, 0x18, 0x0a, 0xbc, 0x0d],
+    };
+
+    #[test]
+    fn describes_the_board() {
+        let buses = [BusType::Spi as i32, BUS_GPIO, 0x7fff, BUS_STREAM];
+        assert_eq!(
+            txt(&DEVICE, &buses),
+            [
+                ("chip", "esp32c3".to_owned()),
+                ("fw", "0.1.0".to_owned()),
+                ("buses", "spi,gpio,stream".to_owned()),
+                ("mac", "34:85:18:0a:bc:0d".to_owned()),
+            ]
+        );
+        assert_eq!(txt(&DEVICE, &[])[2], ("buses", String::new()));
+    }
+
+    #[test]
+    fn names_boards_after_their_mac() {
+        assert_eq!(hostname("esp32-bridge", &DEVICE), "esp32-bridge-0abc0d");
+    }
+
+    #[test]
+    fn finds_service_types_in_urls() {
+        assert_eq!(
+            service("ws://_bridge-host._tcp.local/path"),
+            Some("_bridge-host")
+        );
+        assert_eq!(
+            service("wss://_bridge-host._tcp:8765"),
+            Some("_bridge-host")
+        );
+        for url in [
+            "ws://192.168.1.10:8765",
+            "ws://host.local/_bridge._tcp",
+            "ws://bridge-host._tcp.local",
+            "ws://_._tcp.local",
+            "_bridge-host._tcp.local",
+        ] {
+            assert_eq!(service(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn points_urls_at_resolved_addresses() {
+        let addr: SocketAddr = "192.168.1.10:8765".parse().unwrap();
+        assert_eq!(
+            with_address("ws://_bridge-host._tcp.local/bridge?v=1", addr),
+            "ws://192.168.1.10:8765/bridge?v=1"
+        );
+        assert_eq!(
+            with_address("wss://_bridge-host._tcp.local", addr),
+            "wss://192.168.1.10:8765"
+        );
+        let addr: SocketAddr = "[fe80::1]:443".parse().unwrap();
+        assert_eq!(with_address("wss://_h._tcp/", addr), "wss://[fe80::1]:443/");
+        assert_eq!(with_address("not a url", addr), "not a url");
+    }
+}
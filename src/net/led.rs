@@ -0,0 +1,43 @@
+//! [`Link`] state on the WS2812 RGB LED of the board.
+
+use smart_leds::{SmartLedsWrite, RGB8};
+use tokio::sync::watch;
+use ws2812_esp32_rmt_driver::Ws2812Esp32Rmt;
+
+use super::Link;
+
+pub struct StatusLed<'d> {
+    ws2812: Ws2812Esp32Rmt<'d>,
+}
+
+impl<'d> StatusLed<'d> {
+    pub fn new(ws2812: Ws2812Esp32Rmt<'d>) -> Self {
+        Self { ws2812 }
+    }
+
+    /// Off when offline, yellow while connecting, green once connected and
+    /// red while waiting to retry.
+    pub fn show(&mut self, link: Link) -> anyhow::Result<()> {
+        let color = match link {
+            Link::Offline => RGB8::new(0, 0, 0),
+            Link::Connecting => RGB8::new(30, 20, 0),
+            Link::Connected => RGB8::new(0, 30, 0),
+            Link::Backoff => RGB8::new(30, 0, 0),
+        };
+        self.ws2812.write(std::iter::once(color))?;
+        Ok(())
+    }
+
+    /// Follow `link` until its sender is dropped.
+    pub async fn run(mut self, mut link: watch::Receiver<Link>) {
+        loop {
+            let state = *link.borrow_and_update();
+            if let Err(e) = self.show(state) {
+                log::error!("Status LED: {}", e);
+            }
+            if link.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
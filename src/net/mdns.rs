@@ -0,0 +1,84 @@
+//! Announcing the board on the local network with mDNS, so host tools find
+//! it without knowing its address, and finding servers the same way.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::mdns::{EspMdns, QueryResult};
+
+/// Service type of the WebSocket listener.
+pub const SERVICE_WS: &str = "_esp-bridge-ws";
+/// Service type of the raw TCP listener, see [`delimited`](super::delimited).
+pub const SERVICE_TCP: &str = "_esp-bridge";
+/// Service type describing the board, announced whether it listens or
+/// dials a server.
+pub const SERVICE_INFO: &str = "_esp-bridge-info";
+const PROTO: &str = "_tcp";
+
+/// Port of [`SERVICE_INFO`], which mDNS requires though nothing is served:
+/// the discard port.
+const INFO_PORT: u16 = 9;
+
+/// How long [`Mdns::resolve`] waits for answers.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The mDNS responder, answering as long as it is kept.
+pub struct Mdns {
+    /// Shared with the blocking queries of [`resolve`](Self::resolve).
+    mdns: Arc<Mutex<EspMdns>>,
+    txt: Vec<(&'static str, String)>,
+}
+
+impl Mdns {
+    /// Answer for `hostname.local` and announce [`SERVICE_INFO`], the
+    /// services announced carrying `txt`, see
+    /// [`discovery::txt`](super::discovery::txt).
+    pub fn new(hostname: &str, txt: Vec<(&'static str, String)>) -> anyhow::Result<Self> {
+        let mut mdns = EspMdns::take()?;
+        mdns.set_hostname(hostname)?;
+        mdns.set_instance_name(hostname)?;
+        log::info!("Answering mDNS as {}.local", hostname);
+        let mut this = Self {
+            mdns: Arc::new(Mutex::new(mdns)),
+            txt,
+        };
+        this.announce(SERVICE_INFO, INFO_PORT)?;
+        Ok(this)
+    }
+
+    /// Announce a listener of `service`, e.g. [`SERVICE_WS`], on `port`.
+    pub fn announce(&mut self, service: &str, port: u16) -> anyhow::Result<()> {
+        let txt: Vec<(&str, &str)> = self.txt.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        self.mdns
+            .lock()
+            .unwrap()
+            .add_service(None, service, PROTO, port, &txt)?;
+        log::info!("Announcing {}.{} on port {}", service, PROTO, port);
+        Ok(())
+    }
+
+    /// Address of the first instance of `service` answering, e.g. the
+    /// server named by a [`discovery::service`](super::discovery::service)
+    /// URL. The query waits for up to 3 s on a blocking thread, leaving
+    /// the runtime to the other tasks.
+    pub async fn resolve(&self, service: &str) -> anyhow::Result<SocketAddr> {
+        let (mdns, service) = (self.mdns.clone(), service.to_owned());
+        tokio::task::spawn_blocking(move || query(&mdns.lock().unwrap(), &service)).await?
+    }
+}
+
+/// The blocking part of [`Mdns::resolve`].
+fn query(mdns: &EspMdns, service: &str) -> anyhow::Result<SocketAddr> {
+    let mut results = [QueryResult::default()];
+    let found = mdns.query_ptr(service, PROTO, QUERY_TIMEOUT, 1, &mut results)?;
+    let result = &results[..found.min(1)];
+    let Some((addr, port)) = result
+        .iter()
+        .find_map(|result| Some((*result.addr.first()?, result.port)))
+    else {
+        anyhow::bail!("No {}.{} found", service, PROTO);
+    };
+    log::info!("Resolved {}.{} to {}:{}", service, PROTO, addr, port);
+    Ok(SocketAddr::new(addr, port))
+}
@@ -0,0 +1,85 @@
+//! Keeping the board connected to the host: Wi-Fi, reconnection and the
+//! status shown on the RGB LED.
+
+use core::fmt;
+
+pub mod backoff;
+#[cfg(target_os = "espidf")]
+pub mod ble;
+pub mod captive;
+pub mod config;
+pub mod delimited;
+pub mod discovery;
+#[cfg(target_os = "espidf")]
+pub mod led;
+#[cfg(target_os = "espidf")]
+pub mod mdns;
+#[cfg(target_os = "espidf")]
+pub mod portal;
+#[cfg(target_os = "espidf")]
+pub mod store;
+#[cfg(target_os = "espidf")]
+pub mod tls;
+#[cfg(target_os = "espidf")]
+pub mod wifi;
+
+/// State of the link to the host, published with a `tokio::sync::watch`
+/// channel for the status LED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Link {
+    /// No server to connect to, or no network to reach it.
+    #[default]
+    Offline,
+    Connecting,
+    Connected,
+    /// Waiting before the next attempt after a failure.
+    Backoff,
+}
+
+/// A Wi-Fi network to join, an empty password meaning an open network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+    pub ssid: String,
+    pub password: String,
+}
+
+impl Network {
+    pub fn new(ssid: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            ssid: ssid.into(),
+            password: password.into(),
+        }
+    }
+}
+
+/// State of the Wi-Fi station, published with a `tokio::sync::watch`
+/// channel.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum WifiState {
+    #[default]
+    Down,
+    Connecting {
+        ssid: String,
+    },
+    /// Associated and holding an IP address, `rssi` in dBm.
+    Up {
+        ssid: String,
+        rssi: i8,
+    },
+}
+
+impl WifiState {
+    pub fn is_up(&self) -> bool {
+        matches!(self, Self::Up { .. })
+    }
+}
+
+impl fmt::Display for WifiState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Down => f.write_str("down"),
+            Self::Connecting { ssid } => write!(f, "connecting to {}", ssid),
+            Self::Up { ssid, rssi } => write!(f, "connected to {} ({} dBm)", ssid, rssi),
+        }
+    }
+}
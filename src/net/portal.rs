@@ -0,0 +1,163 @@
+//! Provisioning over a SoftAP: the board opens its own access point and
+//! serves a page to choose the network and the server, then reboots into
+//! station mode.
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi,
+};
+
+use super::captive;
+use super::store::NetStore;
+
+/// Longest form accepted, a network, a password and a URL.
+const MAX_FORM_LEN: usize = 1024;
+
+/// Run the portal on an open access point called `ssid` until a
+/// configuration is saved to `store`, then restart the chip.
+pub fn run(
+    wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    store: NetStore,
+    ssid: &str,
+) -> anyhow::Result<()> {
+    let mut wifi = BlockingWifi::wrap(wifi, sysloop)?;
+    // The station half is only used to scan
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        AccessPointConfiguration {
+            ssid: ssid
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("SSID {} is too long", ssid))?,
+            auth_method: AuthMethod::None,
+            channel: 1,
+            ..Default::default()
+        },
+    ))?;
+    wifi.start()?;
+    // The station is never connected, only the access point comes up
+    wifi.ip_wait_while(
+        |wifi| wifi.ap_netif().is_up().map(|up| !up),
+        Some(Duration::from_secs(10)),
+    )?;
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    log::info!("Provisioning portal on {} at http://{}", ssid, ip);
+
+    let mut scan: Vec<(String, i8)> = wifi
+        .scan()?
+        .into_iter()
+        .map(|ap| (ap.ssid.to_string(), ap.signal_strength))
+        .filter(|(ssid, _)| !ssid.is_empty())
+        .collect();
+    // Strongest access point of each network first
+    scan.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    scan.dedup_by(|a, b| a.0 == b.0);
+    scan.sort_by_key(|(_, rssi)| -(*rssi as i16));
+    log::info!("{} networks in range", scan.len());
+
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            if let Err(e) = dns(ip) {
+                log::error!("Captive DNS stopped: {:#}", e);
+            }
+        })?;
+
+    let config = Arc::new(Mutex::new(store.load()?));
+    let store = Arc::new(Mutex::new(store));
+    let saved = Arc::new(AtomicBool::new(false));
+
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    {
+        let (scan, config) = (scan.clone(), config.clone());
+        server.fn_handler("/", Method::Get, move |req| -> anyhow::Result<()> {
+            let html = captive::page(&scan, &config.lock().unwrap(), "");
+            req.into_ok_response()?.write_all(html.as_bytes())?;
+            Ok(())
+        })?;
+    }
+
+    {
+        let saved = saved.clone();
+        server.fn_handler(
+            "/save",
+            Method::Post,
+            move |mut req| -> anyhow::Result<()> {
+                let len = req.content_len().unwrap_or(0) as usize;
+                if len > MAX_FORM_LEN {
+                    req.into_status_response(413)?;
+                    return Ok(());
+                }
+                let mut body = vec![0; len];
+                let mut read = 0;
+                while read < len {
+                    match req.read(&mut body[read..])? {
+                        0 => break,
+                        n => read += n,
+                    }
+                }
+                body.truncate(read);
+                let form = captive::parse_form(&String::from_utf8_lossy(&body));
+
+                let mut config = config.lock().unwrap();
+                let result = captive::apply_form(&config, &form).and_then(|new| {
+                    store.lock().unwrap().save(&new)?;
+                    Ok(new)
+                });
+                let message = match result {
+                    Ok(new) => {
+                        *config = new;
+                        saved.store(true, Ordering::SeqCst);
+                        "Saved, rebooting".to_owned()
+                    }
+                    Err(e) => format!("Not saved: {:#}", e),
+                };
+                let html = captive::page(&scan, &config, &message);
+                req.into_ok_response()?.write_all(html.as_bytes())?;
+                Ok(())
+            },
+        )?;
+    }
+
+    // Connectivity checks of phones and laptops land here, the redirection
+    // makes them show the portal
+    server.fn_handler("/*", Method::Get, move |req| -> anyhow::Result<()> {
+        let location = format!("http://{}/", ip);
+        req.into_response(302, Some("Found"), &[("Location", &location)])?;
+        Ok(())
+    })?;
+
+    while !saved.load(Ordering::SeqCst) {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    log::info!("Configuration saved, restarting");
+    // Lets the reply reach the browser
+    std::thread::sleep(Duration::from_secs(1));
+    drop(server);
+    // SAFETY: nothing is left to flush
+    unsafe { esp_idf_svc::sys::esp_restart() }
+}
+
+/// Answer every DNS query with `ip`.
+fn dns(ip: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53))?;
+    let mut buf = [0; 512];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        if let Some(reply) = captive::dns_reply(&buf[..len], ip) {
+            socket.send_to(&reply, peer)?;
+        }
+    }
+}
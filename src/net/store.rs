@@ -0,0 +1,167 @@
+//! [`NetConfig`] kept in flash, in its own NVS namespace.
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+use super::config::{validate_secret, validate_url, NetConfig, TlsConfig, MAX_NETWORKS};
+use super::Network;
+
+const NAMESPACE: &str = "net";
+const SERVER_URL: &str = "server_url";
+const CA_CERT: &str = "ca_cert";
+const CLIENT_CERT: &str = "client_cert";
+const CLIENT_KEY: &str = "client_key";
+const AUTH_SECRET: &str = "auth_secret";
+
+pub struct NetStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NetStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    /// The configuration in NVS, completed with
+    /// [`NetConfig::build_defaults`]: the build-time networks come after the
+    /// stored ones, and the server URL, each certificate and the secret are
+    /// used when not stored. Values failing [`NetConfig::validate`] are
+    /// dropped with a warning, so a bad one does not keep the board from
+    /// booting into provisioning.
+    pub fn load(&self) -> anyhow::Result<NetConfig> {
+        let defaults = NetConfig::build_defaults();
+
+        let mut networks = Vec::new();
+        for i in 0..MAX_NETWORKS {
+            let Some(ssid) = self.get(&format!("ssid{}", i))? else {
+                break;
+            };
+            let password = self.get(&format!("passwd{}", i))?.unwrap_or_default();
+            networks.push(Network::new(ssid, password));
+        }
+        if !networks.is_empty() {
+            log::info!("{} Wi-Fi networks loaded from NVS", networks.len());
+        }
+        for network in defaults.networks {
+            if !networks.iter().any(|n| n.ssid == network.ssid) {
+                networks.push(network);
+            }
+        }
+        networks.retain(|network| valid("Network", network, Network::validate));
+        networks.truncate(MAX_NETWORKS);
+
+        let server_url = self
+            .get(SERVER_URL)?
+            .or(defaults.server_url)
+            .filter(|url| valid("Server URL", url.as_str(), validate_url));
+
+        let mut tls = TlsConfig {
+            ca_cert: self.get_pem(CA_CERT)?.or(defaults.tls.ca_cert),
+            client_cert: self.get_pem(CLIENT_CERT)?.or(defaults.tls.client_cert),
+            client_key: self.get_pem(CLIENT_KEY)?.or(defaults.tls.client_key),
+        };
+        if !valid("TLS configuration", &tls, TlsConfig::validate) {
+            tls = TlsConfig::default();
+        }
+
+        let auth_secret = self
+            .get(AUTH_SECRET)?
+            .or(defaults.auth_secret)
+            .filter(|secret| valid("Secret", secret.as_str(), validate_secret));
+
+        Ok(NetConfig {
+            networks,
+            server_url,
+            tls,
+            auth_secret,
+        })
+    }
+
+    /// Replace the configuration in NVS with `config`, once validated. Only
+    /// what differs from [`NetConfig::build_defaults`] is stored, so the
+    /// values of a later build are still picked up by [`load`](Self::load).
+    pub fn save(&mut self, config: &NetConfig) -> anyhow::Result<()> {
+        config.validate()?;
+        let defaults = NetConfig::build_defaults();
+
+        let networks: Vec<_> = config
+            .networks
+            .iter()
+            .filter(|network| !defaults.networks.contains(network))
+            .collect();
+        for i in 0..MAX_NETWORKS {
+            let (ssid, passwd) = (format!("ssid{}", i), format!("passwd{}", i));
+            match networks.get(i) {
+                Some(network) => {
+                    self.nvs.set_str(&ssid, &network.ssid)?;
+                    self.nvs.set_str(&passwd, &network.password)?;
+                }
+                None => {
+                    self.nvs.remove(&ssid)?;
+                    self.nvs.remove(&passwd)?;
+                }
+            }
+        }
+        for (key, value, default) in [
+            (SERVER_URL, &config.server_url, &defaults.server_url),
+            (AUTH_SECRET, &config.auth_secret, &defaults.auth_secret),
+        ] {
+            match value {
+                Some(value) if Some(value) != default.as_ref() => self.nvs.set_str(key, value)?,
+                _ => {
+                    self.nvs.remove(key)?;
+                }
+            }
+        }
+        for (key, pem, default) in [
+            (CA_CERT, &config.tls.ca_cert, &defaults.tls.ca_cert),
+            (
+                CLIENT_CERT,
+                &config.tls.client_cert,
+                &defaults.tls.client_cert,
+            ),
+            (CLIENT_KEY, &config.tls.client_key, &defaults.tls.client_key),
+        ] {
+            match pem {
+                Some(pem) if Some(pem) != default.as_ref() => {
+                    self.nvs.set_blob(key, pem.as_bytes())?
+                }
+                _ => {
+                    self.nvs.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Certificates are blobs, longer than NVS strings may be.
+    fn get_pem(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Some(len) = self.nvs.blob_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len];
+        let pem = self.nvs.get_blob(key, &mut buf)?;
+        Ok(pem.map(|pem| String::from_utf8_lossy(pem).into_owned()))
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Some(len) = self.nvs.str_len(key)? else {
+            return Ok(None);
+        };
+        // Room for the NUL terminator, whether counted or not
+        let mut buf = vec![0; len + 1];
+        Ok(self.nvs.get_str(key, &mut buf)?.map(str::to_owned))
+    }
+}
+
+/// Whether `value` passes `check`, logging why not.
+fn valid<T: ?Sized>(name: &str, value: &T, check: impl FnOnce(&T) -> anyhow::Result<()>) -> bool {
+    match check(value) {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("{} ignored: {:#}", name, e);
+            false
+        }
+    }
+}
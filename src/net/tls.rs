@@ -0,0 +1,156 @@
+//! `wss://` over the TLS of ESP-IDF: mbedTLS runs on a tokio socket and
+//! the plaintext reaches `tokio_websockets` through a duplex pipe.
+
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, IntoRawFd};
+use std::task::{Context, Poll};
+
+use esp_idf_svc::sys::{EspError, ESP_FAIL};
+use esp_idf_svc::tls::{Config, EspAsyncTls, PollableSocket, Socket, X509};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, Interest};
+use tokio::net::TcpStream;
+
+use super::config::{endpoint, TlsConfig};
+
+/// Plaintext buffered each way between TLS and the WebSocket.
+const PIPE_LEN: usize = 2048;
+
+/// Open a TLS connection to the host of `url`, verified against the pinned
+/// certificate of `tls` or the CA bundle of the firmware.
+///
+/// Returns the plaintext end of the connection and the future moving the
+/// data through TLS, to be polled as long as the connection is used.
+pub async fn connect(
+    url: &str,
+    tls: &TlsConfig,
+) -> anyhow::Result<(DuplexStream, impl Future<Output = anyhow::Result<()>>)> {
+    let (host, port) = endpoint(url)?;
+    let stream = TcpStream::connect((host, port)).await?;
+    let mut session = EspAsyncTls::adopt(TokioSocket(Some(stream)))?;
+
+    let ca_cert = tls.ca_cert.as_deref().map(nul_terminated);
+    let client_cert = tls.client_cert.as_deref().map(nul_terminated);
+    let client_key = tls.client_key.as_deref().map(nul_terminated);
+    let config = Config {
+        ca_cert: ca_cert.as_deref().map(X509::pem_until_nul),
+        client_cert: client_cert.as_deref().map(X509::pem_until_nul),
+        client_key: client_key.as_deref().map(X509::pem_until_nul),
+        common_name: Some(host),
+        use_crt_bundle_attach: ca_cert.is_none(),
+        ..Default::default()
+    };
+    session.negotiate(host, &config).await?;
+    log::info!(
+        "TLS established with {}{}",
+        host,
+        if client_cert.is_some() {
+            ", client certificate presented"
+        } else {
+            ""
+        }
+    );
+
+    let (plain, pipe) = tokio::io::duplex(PIPE_LEN);
+    Ok((plain, pump(session, pipe)))
+}
+
+/// Move the data between `session` and `pipe` until either is closed.
+async fn pump(session: EspAsyncTls<TokioSocket>, pipe: DuplexStream) -> anyhow::Result<()> {
+    let (mut from_pipe, mut to_pipe) = tokio::io::split(pipe);
+
+    let incoming = async {
+        let mut buf = [0; 512];
+        loop {
+            let len = session.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            to_pipe.write_all(&buf[..len]).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    let outgoing = async {
+        let mut buf = [0; 512];
+        loop {
+            let len = from_pipe.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            let mut sent = 0;
+            while sent < len {
+                sent += session.write(&buf[sent..len]).await?;
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    tokio::select! {
+        result = incoming => result,
+        result = outgoing => result,
+    }
+}
+
+/// PEM as mbedTLS parses it, ending with a nul byte.
+fn nul_terminated(pem: &str) -> Vec<u8> {
+    let mut pem = pem.trim_end_matches('\0').as_bytes().to_vec();
+    pem.push(0);
+    pem
+}
+
+/// A tokio socket lent to esp-tls, which closes it when done.
+struct TokioSocket(Option<TcpStream>);
+
+impl Socket for TokioSocket {
+    fn handle(&self) -> i32 {
+        self.0.as_ref().map_or(-1, AsRawFd::as_raw_fd)
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        // Leaves the descriptor to esp-tls rather than closing it twice
+        if let Some(stream) = self.0.take() {
+            let stream = stream.into_std().map_err(|_| fail())?;
+            let _ = stream.into_raw_fd();
+        }
+        Ok(())
+    }
+}
+
+impl TokioSocket {
+    /// Poll the readiness of `interest`, clearing it once ready: esp-tls
+    /// does the IO on the descriptor itself, so tokio never sees it would
+    /// block and would report the socket ready again and again.
+    fn poll_ready(&self, cx: &mut Context, interest: Interest) -> Poll<Result<(), EspError>> {
+        let Some(stream) = &self.0 else {
+            return Poll::Ready(Err(fail()));
+        };
+        let ready = if interest == Interest::READABLE {
+            stream.poll_read_ready(cx)
+        } else {
+            stream.poll_write_ready(cx)
+        };
+        match ready {
+            Poll::Ready(Ok(())) => {
+                let _ = stream.try_io(interest, || Err::<(), _>(io::ErrorKind::WouldBlock.into()));
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(Err(fail())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl PollableSocket for TokioSocket {
+    fn poll_readable(&self, cx: &mut Context) -> Poll<Result<(), EspError>> {
+        self.poll_ready(cx, Interest::READABLE)
+    }
+
+    fn poll_writable(&self, cx: &mut Context) -> Poll<Result<(), EspError>> {
+        self.poll_ready(cx, Interest::WRITABLE)
+    }
+}
+
+fn fail() -> EspError {
+    EspError::from_infallible::<ESP_FAIL>()
+}
@@ -0,0 +1,229 @@
+//! Wi-Fi station kept connected, see [`WifiManager`].
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use esp_idf_svc::eventloop::{EspSubscription, EspSystemEventLoop, System};
+use esp_idf_svc::sys::{esp, esp_wifi_sta_get_ap_info, wifi_ap_record_t};
+use esp_idf_svc::timer::EspTaskTimerService;
+use esp_idf_svc::wifi::{
+    AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi, WifiEvent,
+};
+use tokio::sync::{watch, Notify};
+
+use super::backoff::Backoff;
+use super::{Network, WifiState};
+
+/// How often the RSSI of the access point is refreshed.
+const RSSI_PERIOD: Duration = Duration::from_secs(10);
+
+/// Joins the first reachable network of a list and joins again when the
+/// station is disconnected.
+///
+/// Networks are tried in turn, each failure moving on to the next one, with
+/// a backoff once all of them failed. The state is published for the rest of
+/// the firmware, see [`WifiManager::state`].
+pub struct WifiManager {
+    wifi: AsyncWifi<EspWifi<'static>>,
+    networks: Vec<Network>,
+    updates: Option<watch::Receiver<Vec<Network>>>,
+    current: usize,
+    backoff: Backoff,
+    state: watch::Sender<WifiState>,
+    disconnected: Arc<Notify>,
+    _subscription: EspSubscription<'static, System>,
+}
+
+impl WifiManager {
+    pub fn new(
+        wifi: EspWifi<'static>,
+        sysloop: EspSystemEventLoop,
+        networks: Vec<Network>,
+        backoff: Backoff,
+    ) -> anyhow::Result<Self> {
+        let disconnected = Arc::new(Notify::new());
+        let notify = disconnected.clone();
+        let subscription = sysloop.subscribe::<WifiEvent, _>(move |event| {
+            if let WifiEvent::StaDisconnected(_) = event {
+                notify.notify_one();
+            }
+        })?;
+        let timer = EspTaskTimerService::new()?;
+
+        Ok(Self {
+            wifi: AsyncWifi::wrap(wifi, sysloop, timer)?,
+            networks,
+            updates: None,
+            current: 0,
+            backoff,
+            state: watch::Sender::new(WifiState::Down),
+            disconnected,
+            _subscription: subscription,
+        })
+    }
+
+    /// Switch to the networks sent on `updates`, e.g. by a provisioning
+    /// service, dropping the current connection.
+    pub fn with_updates(mut self, updates: watch::Receiver<Vec<Network>>) -> Self {
+        self.updates = Some(updates);
+        self
+    }
+
+    pub fn state(&self) -> watch::Receiver<WifiState> {
+        self.state.subscribe()
+    }
+
+    /// Keep the station connected, or stay down until a network is
+    /// configured.
+    pub async fn run(mut self) -> ! {
+        loop {
+            if self.networks.is_empty() {
+                log::warn!("No Wi-Fi network configured");
+                let networks = next_update(&mut self.updates).await;
+                self.switch(networks).await;
+                continue;
+            }
+
+            let network = self.networks[self.current].clone();
+            self.state.send_replace(WifiState::Connecting {
+                ssid: network.ssid.clone(),
+            });
+
+            let result = tokio::select! {
+                result = connect(&mut self.wifi, &network) => result,
+                networks = next_update(&mut self.updates) => {
+                    self.switch(networks).await;
+                    continue;
+                }
+            };
+            match result {
+                Ok(()) => {
+                    self.backoff.reset();
+                    if let Some(networks) = self.monitor(&network.ssid).await {
+                        self.switch(networks).await;
+                        continue;
+                    }
+                    log::warn!("Wifi {} lost", network.ssid);
+                }
+                Err(e) => {
+                    log::error!("Wifi {} failed: {:#}", network.ssid, e);
+                    self.state.send_replace(WifiState::Down);
+                    self.current = (self.current + 1) % self.networks.len();
+                    if self.current == 0 {
+                        let delay = self.backoff.next_delay();
+                        log::info!("No network joined, retrying in {:?}", delay);
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => {}
+                            networks = next_update(&mut self.updates) => {
+                                self.switch(networks).await;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drop the current connection and start over with `networks`.
+    async fn switch(&mut self, networks: Vec<Network>) {
+        log::info!("Wi-Fi networks updated");
+        if self.wifi.is_connected().unwrap_or(false) {
+            if let Err(e) = self.wifi.disconnect().await {
+                log::warn!("Wifi disconnect: {}", e);
+            }
+        }
+        self.state.send_replace(WifiState::Down);
+        self.networks = networks;
+        self.current = 0;
+        self.backoff.reset();
+    }
+
+    /// Publish the RSSI until the station is disconnected, or until new
+    /// networks are sent, which are returned.
+    async fn monitor(&mut self, ssid: &str) -> Option<Vec<Network>> {
+        loop {
+            match rssi() {
+                Ok(rssi) => {
+                    let up = WifiState::Up {
+                        ssid: ssid.to_owned(),
+                        rssi,
+                    };
+                    self.state.send_if_modified(|state| {
+                        let modified = *state != up;
+                        *state = up;
+                        modified
+                    });
+                }
+                Err(e) => log::warn!("RSSI not available: {}", e),
+            }
+
+            tokio::select! {
+                _ = self.disconnected.notified() => {}
+                _ = tokio::time::sleep(RSSI_PERIOD) => {}
+                networks = next_update(&mut self.updates) => return Some(networks),
+            }
+            // The notification may be left over from an earlier attempt
+            if !self.wifi.is_connected().unwrap_or(false) {
+                self.state.send_replace(WifiState::Down);
+                return None;
+            }
+        }
+    }
+}
+
+/// Join `network` and wait for an IP address.
+async fn connect(wifi: &mut AsyncWifi<EspWifi<'static>>, network: &Network) -> anyhow::Result<()> {
+    let auth_method = if network.password.is_empty() {
+        log::info!("No password provided, using open network");
+        AuthMethod::None
+    } else {
+        AuthMethod::WPA2Personal
+    };
+    let configuration = Configuration::Client(ClientConfiguration {
+        ssid: network
+            .ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("SSID {} is too long", network.ssid))?,
+        password: network
+            .password
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Password of {} is too long", network.ssid))?,
+        auth_method,
+        ..Default::default()
+    });
+
+    if wifi.is_connected()? {
+        wifi.disconnect().await?;
+    }
+    wifi.set_configuration(&configuration)?;
+    if !wifi.is_started()? {
+        wifi.start().await?;
+    }
+    log::info!("Connecting wifi {}...", network.ssid);
+    wifi.connect().await?;
+    log::info!("Waiting for DHCP lease...");
+    wifi.wait_netif_up().await?;
+    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    log::info!("Wifi DHCP info: {:?}", ip_info);
+    Ok(())
+}
+
+/// The next networks sent on `updates`, never if there is no sender.
+async fn next_update(updates: &mut Option<watch::Receiver<Vec<Network>>>) -> Vec<Network> {
+    if let Some(updates) = updates {
+        if updates.changed().await.is_ok() {
+            return updates.borrow_and_update().clone();
+        }
+    }
+    std::future::pending().await
+}
+
+/// RSSI of the access point the station is associated with, in dBm.
+fn rssi() -> anyhow::Result<i8> {
+    let mut info = wifi_ap_record_t::default();
+    // SAFETY: `info` is a valid record for the driver to fill
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut info) })?;
+    Ok(info.rssi)
+}